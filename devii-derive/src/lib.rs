@@ -0,0 +1,574 @@
+//! `#[derive(Devii)]` generates the `DeviiTrait` impl for a struct from its fields.
+//!
+//! Every field is a column of the Devii table unless its attributes say otherwise:
+//!
+//! - `#[devii(table = "...")]` on the struct sets the table name (defaults to the snake
+//!   cased struct name).
+//! - `#[devii(rename = "...")]` sets the GraphQL name of the field. Fetched values are
+//!   aliased back to the field's serde name so deserializing keeps working. Serde names follow
+//!   `#[serde(rename)]` on the field and `#[serde(rename_all)]` on the struct.
+//! - `#[devii(skip)]` leaves the field out of every query.
+//! - `#[devii(read_only)]` selects the field but never sends it as an input.
+//! - `#[devii(id)]` marks the primary key columns (defaults to the `id` field). A single key
+//!   field's type, without `Option`, becomes `DeviiTrait::Id`; several make a tuple in field order.
+//! - `#[devii(has_many, fk = "...")]` marks a `Vec<T>` as the one to many side of a
+//!   relation whose children point back through their `fk` column (defaults to `<table>_id`).
+//! - `#[devii(belongs_to, fk = "...")]` marks a `T` as the many to one side of a
+//!   relation stored in the `fk` column (defaults to `<field>_id`).
+//!
+//! It also implements `GraphNode`, which `DeviiClient::insert_graph` uses to walk the
+//! relations. A record counts as inserted when all of its keys are `Option`s holding a value. Ids are written back into the key columns, the `fk` columns of `belongs_to`
+//! relations and the columns ending in `_id`.
+//!
+//! The generated code refers to the runtime crate as `::devii`; use
+//! `#[devii(crate = "...")]` when it is reachable under a different path.
+
+extern crate proc_macro;
+
+use convert_case::{Case, Casing};
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, GenericArgument, Lit, Meta, PathArguments, Token, Type};
+
+struct ContainerAttrs {
+    krate: syn::Path,
+    table: Option<String>,
+}
+
+enum FieldKind {
+    Column { read_only: bool },
+    HasMany { ty: Type, fk: String, optional: bool },
+    BelongsTo { ty: Type, fk: String, optional: bool, boxed: bool },
+}
+
+struct DeviiField {
+    ident: syn::Ident,
+    // The field's type without `Option`, and whether it was wrapped in one.
+    ty: Type,
+    optional: bool,
+    key_column: bool,
+    // Name of the field once serialized by serde.
+    key: String,
+    // Name of the column or relation in Devii.
+    name: String,
+    kind: FieldKind,
+}
+
+impl DeviiField {
+    // Fields whose serde name differs from the Devii name are aliased in selections.
+    fn selection_name(&self) -> String {
+        if self.key == self.name {
+            self.name.clone()
+        } else {
+            format!("{}: {}", self.key, self.name)
+        }
+    }
+}
+
+#[proc_macro_derive(Devii, attributes(devii))]
+pub fn derive_devii(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let ContainerAttrs { krate, table } = container_attrs(&input.attrs)?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(syn::Error::new_spanned(ident, "Devii can only be derived for structs with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(ident, "Devii can only be derived for structs")),
+    };
+
+    let table = table.unwrap_or_else(|| ident.to_string().to_case(Case::Snake));
+    let rename_all = serde_rename_all(&input.attrs)?.map(|rule| rule.value());
+
+    let mut fields: Vec<DeviiField> = vec![];
+    for field in named.iter() {
+        if let Some(f) = devii_field(field, &table, rename_all.as_deref())? {
+            fields.push(f);
+        }
+    }
+
+    let mut keys: Vec<&DeviiField> = fields.iter().filter(|f| f.key_column).collect();
+    if keys.is_empty() {
+        keys = fields.iter().filter(|f| f.name == "id").collect();
+    }
+    if keys.is_empty() {
+        return Err(syn::Error::new_spanned(ident, "Devii requires an `id` field or fields marked `#[devii(id)]`"));
+    }
+    if let Some(f) = keys.iter().find(|f| !matches!(f.kind, FieldKind::Column { .. })) {
+        return Err(syn::Error::new_spanned(&f.ident, "`id` can only be used on columns"));
+    }
+    let id_field = &keys[0].ident;
+    let key_names: Vec<&str> = keys.iter().map(|f| f.name.as_str()).collect();
+    let key_fields: Vec<&syn::Ident> = keys.iter().map(|f| &f.ident).collect();
+    let key_types: Vec<&Type> = keys.iter().map(|f| &f.ty).collect();
+    let key_values: Vec<TokenStream2> = keys.iter().map(|f| {
+        let field = &f.ident;
+        if f.optional {
+            quote! { self.#field.clone()? }
+        } else {
+            quote! { self.#field.clone() }
+        }
+    }).collect();
+    // Only `Option` keys tell a new record from an inserted one; other keys are set before insert.
+    let graph_inserted = if keys.iter().all(|f| f.optional) {
+        quote! { <Self as #krate::devii::DeviiTrait>::id(self).is_some() }
+    } else {
+        quote! { false }
+    };
+    let (id_type, id_value) = match keys.as_slice() {
+        [key] => {
+            let field = &key.ident;
+            let ty = &key.ty;
+            if key.optional {
+                (quote! { #ty }, quote! { self.#field.clone() })
+            } else {
+                (quote! { #ty }, quote! { Some(self.#field.clone()) })
+            }
+        },
+        _ => (quote! { ( #( #key_types ),* ) }, quote! { Some(( #( #key_values ),* )) }),
+    };
+
+    let input_type = format!("{}Input", table);
+    let insert_query = format!("create_{} (input: ${{}} ){{{{ {} }}}}", table, key_names.join(" "));
+
+    let columns: Vec<String> = fields.iter()
+        .filter(|f| matches!(f.kind, FieldKind::Column { .. }))
+        .map(|f| f.selection_name())
+        .collect();
+    let scalar_fields = format!("{{ {} }}", columns.join(", "));
+
+    let selections: Vec<TokenStream2> = fields.iter().map(|f| {
+        let name = f.selection_name();
+        match &f.kind {
+            FieldKind::Column { .. } => quote! { #name.to_string() },
+            // Only the scalar columns of related records are selected, so types that relate to
+            // each other or to themselves don't recurse forever.
+            FieldKind::HasMany { ty, .. } => quote! {
+                format!("{} {}", #name, <#ty as #krate::devii::DeviiTrait>::scalar_fields())
+            },
+            FieldKind::BelongsTo { ty, .. } => quote! {
+                format!("{} {}", #name, <#ty as #krate::devii::DeviiTrait>::scalar_fields())
+            },
+        }
+    }).collect();
+
+    // Only writable columns are part of the `TInput` devii object.
+    let inputs: Vec<&DeviiField> = fields.iter()
+        .filter(|f| matches!(f.kind, FieldKind::Column { read_only: false }))
+        .collect();
+    let input_keys: Vec<&str> = inputs.iter().map(|f| f.key.as_str()).collect();
+    let input_names: Vec<&str> = inputs.iter().map(|f| f.name.as_str()).collect();
+
+    let field_infos: Vec<TokenStream2> = fields.iter().map(|f| {
+        let key = &f.key;
+        let name = &f.name;
+        let kind = match &f.kind {
+            FieldKind::Column { read_only: false } => quote! { #krate::devii::FieldKind::Column },
+            FieldKind::Column { read_only: true } => quote! { #krate::devii::FieldKind::ReadOnly },
+            FieldKind::HasMany { fk, .. } => quote! { #krate::devii::FieldKind::HasMany { fk: #fk } },
+            FieldKind::BelongsTo { fk, .. } => quote! { #krate::devii::FieldKind::BelongsTo { fk: #fk } },
+        };
+        let ty = &f.ty;
+        let rust_type = quote!(#ty).to_string().replace(' ', "");
+        let optional = f.optional;
+        quote! {
+            #krate::devii::FieldInfo { name: #key, graphql_name: #name, kind: #kind, rust_type: #rust_type, optional: #optional }
+        }
+    }).collect();
+
+    // `insert_graph` writes back the inserted key, the foreign keys of `belongs_to` relations and
+    // the parent's id into the `fk` of a `has_many`, which is usually one of the `_id` columns.
+    let mut fks: Vec<&str> = vec![];
+    for f in fields.iter() {
+        if let FieldKind::BelongsTo { fk, .. } = &f.kind {
+            if !fields.iter().any(|c| matches!(c.kind, FieldKind::Column { .. }) && c.name == *fk) {
+                return Err(syn::Error::new_spanned(&f.ident, format!("`fk = \"{}\"` has no matching column field", fk)));
+            }
+            fks.push(fk);
+        }
+    }
+    let link_columns: Vec<&DeviiField> = fields.iter()
+        .filter(|f| matches!(f.kind, FieldKind::Column { .. }))
+        .filter(|f| key_names.contains(&f.name.as_str()) || fks.contains(&f.name.as_str()) || f.name.ends_with("_id"))
+        .collect();
+    let link_names: Vec<&str> = link_columns.iter().map(|f| f.name.as_str()).collect();
+    let link_idents: Vec<&syn::Ident> = link_columns.iter().map(|f| &f.ident).collect();
+
+    let parents: Vec<TokenStream2> = fields.iter().filter_map(|f| {
+        let field = &f.ident;
+        match &f.kind {
+            FieldKind::BelongsTo { fk, optional, boxed, .. } => Some(match (optional, boxed) {
+                (true, true) => quote! {
+                    if let Some(parent) = self.#field.as_mut() {
+                        parents.push(#krate::graph::GraphParent { fk: #fk, node: &mut **parent });
+                    }
+                },
+                (true, false) => quote! {
+                    if let Some(parent) = self.#field.as_mut() {
+                        parents.push(#krate::graph::GraphParent { fk: #fk, node: parent });
+                    }
+                },
+                (false, true) => quote! {
+                    parents.push(#krate::graph::GraphParent { fk: #fk, node: &mut *self.#field });
+                },
+                (false, false) => quote! {
+                    parents.push(#krate::graph::GraphParent { fk: #fk, node: &mut self.#field });
+                },
+            }),
+            _ => None,
+        }
+    }).collect();
+
+    let children: Vec<TokenStream2> = fields.iter().filter_map(|f| {
+        let field = &f.ident;
+        match &f.kind {
+            FieldKind::HasMany { fk, optional: true, .. } => Some(quote! {
+                if let Some(items) = self.#field.as_mut() {
+                    children.push(#krate::graph::GraphChildren {
+                        fk: #fk,
+                        nodes: items.iter_mut().map(|c| c as &mut dyn #krate::graph::GraphNode).collect(),
+                    });
+                }
+            }),
+            FieldKind::HasMany { fk, optional: false, .. } => Some(quote! {
+                children.push(#krate::graph::GraphChildren {
+                    fk: #fk,
+                    nodes: self.#field.iter_mut().map(|c| c as &mut dyn #krate::graph::GraphNode).collect(),
+                });
+            }),
+            _ => None,
+        }
+    }).collect();
+
+    Ok(quote! {
+        impl #impl_generics #krate::graph::GraphNode for #ident #ty_generics #where_clause {
+            fn graph_table(&self) -> String {
+                #table.to_string()
+            }
+            fn graph_inputs(&self) -> #krate::__private::serde_json::Value {
+                #krate::devii::DeviiTrait::graphql_inputs(self)
+            }
+            fn graph_id(&self) -> Option<String> {
+                #krate::graph::id_to_string(&self.#id_field)
+            }
+            fn graph_inserted(&self) -> bool {
+                #graph_inserted
+            }
+            fn graph_key_arguments(&self) -> Option<String> {
+                #krate::graph::key_arguments_of(self)
+            }
+            fn graph_id_columns(&self) -> Vec<&'static str> {
+                <Self as #krate::devii::DeviiTrait>::id_columns()
+            }
+            fn set_graph_column(&mut self, column: &str, id: &str) -> Result<(), #krate::error::DeviiError> {
+                match column {
+                    #( #link_names => {
+                        self.#link_idents = #krate::graph::parse_id(id)?;
+                        Ok(())
+                    }, )*
+                    _ => Err(#krate::error::DeviiError::InvalidInput(format!("{} has no column `{}`", #table, column))),
+                }
+            }
+            fn graph_parents(&mut self) -> Vec<#krate::graph::GraphParent<'_>> {
+                #[allow(unused_mut)]
+                let mut parents = vec![];
+                #( #parents )*
+                parents
+            }
+            fn graph_children(&mut self) -> Vec<#krate::graph::GraphChildren<'_>> {
+                #[allow(unused_mut)]
+                let mut children = vec![];
+                #( #children )*
+                children
+            }
+        }
+
+        impl #impl_generics #krate::devii::DeviiTrait for #ident #ty_generics #where_clause {
+            type Id = #id_type;
+            fn insert_query(&self, param: String) -> String {
+                format!(#insert_query, param)
+            }
+            fn input_type(&self) -> String {
+                #input_type.to_string()
+            }
+            fn graphql_inputs(&self) -> #krate::__private::serde_json::Value {
+                #[allow(unused_mut, unused_variables)]
+                let mut map = match #krate::__private::serde_json::to_value(self).unwrap() {
+                    #krate::__private::serde_json::Value::Object(map) => map,
+                    value => return value,
+                };
+                #[allow(unused_mut)]
+                let mut inputs = #krate::__private::serde_json::Map::new();
+                #(
+                    if let Some(value) = map.remove(#input_keys) {
+                        inputs.insert(#input_names.to_string(), value);
+                    }
+                )*
+                #krate::__private::serde_json::Value::Object(inputs)
+            }
+            fn fetch_fields() -> String {
+                let selections: Vec<String> = vec![ #( #selections ),* ];
+                format!("{{ {} }}", selections.join(", "))
+            }
+            fn scalar_fields() -> String {
+                #scalar_fields.to_string()
+            }
+            fn delete_input(&self) -> String {
+                let arguments: Vec<String> = vec![ #(
+                    format!("{}: {}", #key_names, #krate::__private::serde_json::to_value(&self.#key_fields).unwrap())
+                ),* ];
+                arguments.join(", ")
+            }
+            fn id_columns() -> Vec<&'static str> {
+                vec![ #( #key_names ),* ]
+            }
+            fn id(&self) -> Option<Self::Id> {
+                #id_value
+            }
+            fn table_name() -> String {
+                #table.to_string()
+            }
+            fn fields() -> Vec<#krate::devii::FieldInfo> {
+                vec![ #( #field_infos ),* ]
+            }
+        }
+    })
+}
+
+fn container_attrs(attrs: &[syn::Attribute]) -> syn::Result<ContainerAttrs> {
+    let mut container = ContainerAttrs {
+        krate: syn::parse_quote!(::devii),
+        table: None,
+    };
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("devii")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                container.krate = value.parse()?;
+            } else if meta.path.is_ident("table") {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                container.table = Some(value.value());
+            } else {
+                return Err(meta.error("unsupported devii attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(container)
+}
+
+// Returns `None` for fields marked `#[devii(skip)]`.
+fn devii_field(field: &syn::Field, table: &str, rename_all: Option<&str>) -> syn::Result<Option<DeviiField>> {
+    let ident = field.ident.clone().unwrap();
+    let key = match serde_rename(&field.attrs)? {
+        Some(key) => key,
+        None => {
+            let field_name = ident.to_string().trim_start_matches("r#").to_string();
+            match rename_all {
+                Some(rule) => apply_rename_all(rule, &field_name),
+                None => field_name,
+            }
+        }
+    };
+
+    let mut rename = None;
+    let mut skip = false;
+    let mut read_only = false;
+    let mut has_many = false;
+    let mut belongs_to = false;
+    let mut fk = None;
+    let mut key_column = false;
+
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("devii")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                rename = Some(value.value());
+            } else if meta.path.is_ident("skip") {
+                skip = true;
+            } else if meta.path.is_ident("read_only") {
+                read_only = true;
+            } else if meta.path.is_ident("id") {
+                key_column = true;
+            } else if meta.path.is_ident("has_many") {
+                has_many = true;
+            } else if meta.path.is_ident("belongs_to") {
+                belongs_to = true;
+            } else if meta.path.is_ident("fk") {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                fk = Some(value.value());
+            } else {
+                return Err(meta.error("unsupported devii attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    if skip {
+        return Ok(None);
+    }
+    if has_many && belongs_to {
+        return Err(syn::Error::new_spanned(field, "a field can't be both `has_many` and `belongs_to`"));
+    }
+    if fk.is_some() && !belongs_to && !has_many {
+        return Err(syn::Error::new_spanned(field, "`fk` can only be used together with `has_many` or `belongs_to`"));
+    }
+
+    let name = rename.unwrap_or_else(|| key.clone());
+    let optional = inner_type(&field.ty, "Option").is_some();
+    let ty = inner_type(&field.ty, "Option").unwrap_or(&field.ty);
+
+    let kind = if has_many {
+        match inner_type(ty, "Vec") {
+            Some(item) => FieldKind::HasMany {
+                ty: item.clone(),
+                fk: fk.unwrap_or_else(|| format!("{}_id", table)),
+                optional,
+            },
+            None => return Err(syn::Error::new_spanned(&field.ty, "`has_many` fields must be a `Vec<T>` or `Option<Vec<T>>`")),
+        }
+    } else if belongs_to {
+        let boxed = inner_type(ty, "Box").is_some();
+        let ty = inner_type(ty, "Box").unwrap_or(ty);
+        FieldKind::BelongsTo { ty: ty.clone(), fk: fk.unwrap_or_else(|| format!("{}_id", name)), optional, boxed }
+    } else {
+        FieldKind::Column { read_only }
+    };
+
+    Ok(Some(DeviiField { ident, ty: ty.clone(), optional, key_column, key, name, kind }))
+}
+
+// Picks up `#[serde(rename = "...")]` so inputs and aliases use the serialized name.
+fn serde_rename(attrs: &[syn::Attribute]) -> syn::Result<Option<String>> {
+    for attr in attrs.iter().filter(|a| a.path().is_ident("serde")) {
+        let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+        for meta in metas {
+            if let Meta::NameValue(nv) = meta {
+                if !nv.path.is_ident("rename") {
+                    continue;
+                }
+                if let Expr::Lit(lit) = &nv.value {
+                    if let Lit::Str(s) = &lit.lit {
+                        return Ok(Some(s.value()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Picks up the container's `#[serde(rename_all = "...")]`, or the `serialize` rule of
+// `#[serde(rename_all(serialize = "...", deserialize = "..."))]`, as the serialized names are the
+// ones sent to Devii.
+fn serde_rename_all(attrs: &[syn::Attribute]) -> syn::Result<Option<syn::LitStr>> {
+    for attr in attrs.iter().filter(|a| a.path().is_ident("serde")) {
+        let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+        for meta in metas {
+            let rule = match meta {
+                Meta::NameValue(nv) if nv.path.is_ident("rename_all") => Some(lit_str(&nv.value)?),
+                Meta::List(list) if list.path.is_ident("rename_all") => {
+                    let nested = list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+                    let mut serialize = None;
+                    for meta in nested {
+                        if let Meta::NameValue(nv) = meta {
+                            if nv.path.is_ident("serialize") {
+                                serialize = Some(lit_str(&nv.value)?);
+                            }
+                        }
+                    }
+                    serialize
+                }
+                _ => None,
+            };
+            if let Some(rule) = rule {
+                if !RENAME_RULES.contains(&rule.value().as_str()) {
+                    return Err(syn::Error::new_spanned(&rule, format!("unknown `rename_all` rule `{}`", rule.value())));
+                }
+                return Ok(Some(rule));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn lit_str(expr: &Expr) -> syn::Result<syn::LitStr> {
+    match expr {
+        Expr::Lit(syn::ExprLit { lit: Lit::Str(s), .. }) => Ok(s.clone()),
+        _ => Err(syn::Error::new_spanned(expr, "expected a string literal")),
+    }
+}
+
+const RENAME_RULES: [&str; 8] = [
+    "lowercase", "UPPERCASE", "PascalCase", "camelCase",
+    "snake_case", "SCREAMING_SNAKE_CASE", "kebab-case", "SCREAMING-KEBAB-CASE",
+];
+
+// Renames a snake case field the way serde's `rename_all` does.
+fn apply_rename_all(rule: &str, field: &str) -> String {
+    let pascal = || {
+        let mut pascal = String::new();
+        let mut capitalize = true;
+        for ch in field.chars() {
+            if ch == '_' {
+                capitalize = true;
+            } else if capitalize {
+                pascal.push(ch.to_ascii_uppercase());
+                capitalize = false;
+            } else {
+                pascal.push(ch);
+            }
+        }
+        pascal
+    };
+    match rule {
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" => pascal(),
+        "camelCase" => {
+            let pascal = pascal();
+            match pascal.chars().next() {
+                Some(first) => first.to_ascii_lowercase().to_string() + &pascal[first.len_utf8()..],
+                None => pascal,
+            }
+        }
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.to_ascii_uppercase().replace('_', "-"),
+        _ => field.to_string(),
+    }
+}
+
+fn last_segment(ty: &Type) -> Option<&syn::PathSegment> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last(),
+        _ => None,
+    }
+}
+
+// Returns `T` when `ty` is `wrapper<T>`.
+fn inner_type<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let segment = last_segment(ty)?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(t) => Some(t),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,324 @@
+//! Aggregate queries. Devii computes aggregates under the `Aggregates` root field, one field per
+//! function whose `subject` is a `table.column`:
+//!
+//! ```text
+//! Aggregates {
+//!     a_0: sum(subject: "test_struct._u8", filter: $filter, group_by: ["test_struct._char"])
+//! }
+//! ```
+//!
+//! Without `group_by` each function returns a single value. With it, a list with one
+//! `{ group, value }` per group, where `group` holds the group-by values in order.
+
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::devii::{DeviiClient, DeviiQueryResult, DeviiTrait, Operation};
+use crate::error::DeviiError;
+
+/// An aggregate function Devii computes over a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max
+}
+
+impl AggregateFunction {
+    fn name(&self) -> &'static str {
+        match self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max"
+        }
+    }
+}
+
+/// The aggregates to compute over the records of `T`, e.g.
+/// `Aggregate::<TestStruct>::new().count().sum("_u8").group_by("_char")`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate<T> {
+    functions: Vec<(AggregateFunction, String)>,
+    filter: Option<String>,
+    group_by: Vec<String>,
+    table: PhantomData<T>
+}
+
+impl<T> Default for Aggregate<T> {
+    fn default() -> Self {
+        Aggregate { functions: vec![], filter: None, group_by: vec![], table: PhantomData }
+    }
+}
+
+impl<T: DeviiTrait> Aggregate<T> {
+    pub fn new() -> Self {
+        Aggregate::default()
+    }
+
+    /// Counts the records.
+    pub fn count(mut self) -> Self {
+        let column = T::id_columns()[0].to_string();
+        self.functions.push((AggregateFunction::Count, column));
+        self
+    }
+
+    pub fn sum(self, column: impl Into<String>) -> Self {
+        self.function(AggregateFunction::Sum, column)
+    }
+
+    pub fn avg(self, column: impl Into<String>) -> Self {
+        self.function(AggregateFunction::Avg, column)
+    }
+
+    pub fn min(self, column: impl Into<String>) -> Self {
+        self.function(AggregateFunction::Min, column)
+    }
+
+    pub fn max(self, column: impl Into<String>) -> Self {
+        self.function(AggregateFunction::Max, column)
+    }
+
+    /// Computes `function` over `column`. Nulls are left out, as in SQL.
+    pub fn function(mut self, function: AggregateFunction, column: impl Into<String>) -> Self {
+        self.functions.push((function, column.into()));
+        self
+    }
+
+    /// Only aggregates the records matching `filter`, a filter string or a `devii::filter::Filter`.
+    pub fn filter(mut self, filter: impl Into<String>) -> Self {
+        self.filter = Some(filter.into());
+        self
+    }
+
+    /// Computes the aggregates once per distinct value of `column`.
+    pub fn group_by(mut self, column: impl Into<String>) -> Self {
+        self.group_by.push(column.into());
+        self
+    }
+}
+
+/// The aggregates of one group, or of every record when nothing is grouped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateRow {
+    group: Map<String, Value>,
+    values: Vec<(AggregateFunction, String, Value)>
+}
+
+impl AggregateRow {
+    /// The group-by columns and their values for this group.
+    pub fn group(&self) -> &Map<String, Value> {
+        &self.group
+    }
+
+    /// The result of `function` over `column`, `None` when it is null, e.g. the sum of no records.
+    pub fn get<V: DeserializeOwned>(&self, function: AggregateFunction, column: &str) -> Result<Option<V>, DeviiError> {
+        let value = self.values.iter()
+            .find(|(f, c, _)| *f == function && c == column)
+            .map(|(_, _, value)| value)
+            .ok_or_else(|| DeviiError::MissingData { field: format!("{}({})", function.name(), column) })?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        decode_number(value).map(Some)
+    }
+
+    /// The number of records in the group.
+    pub fn count(&self) -> Result<u64, DeviiError> {
+        let (_, column, _) = self.values.iter()
+            .find(|(f, _, _)| *f == AggregateFunction::Count)
+            .ok_or_else(|| DeviiError::MissingData { field: "count".to_string() })?;
+        Ok(self.get(AggregateFunction::Count, column)?.unwrap_or(0))
+    }
+
+    pub fn sum<V: DeserializeOwned>(&self, column: &str) -> Result<Option<V>, DeviiError> {
+        self.get(AggregateFunction::Sum, column)
+    }
+
+    pub fn avg<V: DeserializeOwned>(&self, column: &str) -> Result<Option<V>, DeviiError> {
+        self.get(AggregateFunction::Avg, column)
+    }
+
+    pub fn min<V: DeserializeOwned>(&self, column: &str) -> Result<Option<V>, DeviiError> {
+        self.get(AggregateFunction::Min, column)
+    }
+
+    pub fn max<V: DeserializeOwned>(&self, column: &str) -> Result<Option<V>, DeviiError> {
+        self.get(AggregateFunction::Max, column)
+    }
+}
+
+// Postgres returns numeric and bigint aggregates, which Devii may hand out as strings.
+fn decode_number<V: DeserializeOwned>(value: &Value) -> Result<V, DeviiError> {
+    serde_json::from_value(value.clone()).or_else(|source| match value {
+        Value::String(s) => serde_json::from_str(s).map_err(|_| DeviiError::Decode { body: value.to_string(), source }),
+        _ => Err(DeviiError::Decode { body: value.to_string(), source })
+    })
+}
+
+impl DeviiClient {
+    /// How many records of `T` match `filter`, a filter string or a `devii::filter::Filter`. An
+    /// empty filter counts every record.
+    pub async fn count<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<u64, DeviiError> {
+        let rows = self.aggregate(&count_aggregate::<T>(filter.into())).await?;
+        single_row(rows)?.count()
+    }
+
+    pub fn count_sync<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<u64, DeviiError> {
+        let rows = self.aggregate_sync(&count_aggregate::<T>(filter.into()))?;
+        single_row(rows)?.count()
+    }
+
+    /// Computes `aggregate` in one request. Returns one row per group, in the order Devii returns
+    /// them, or a single row when nothing is grouped.
+    pub async fn aggregate<T: DeviiTrait>(&self, aggregate: &Aggregate<T>) -> Result<Vec<AggregateRow>, DeviiError> {
+        self.run(aggregate_operation(aggregate)?).await
+    }
+
+    pub fn aggregate_sync<T: DeviiTrait>(&self, aggregate: &Aggregate<T>) -> Result<Vec<AggregateRow>, DeviiError> {
+        self.run_sync(aggregate_operation(aggregate)?)
+    }
+}
+
+fn count_aggregate<T: DeviiTrait>(filter: String) -> Aggregate<T> {
+    let aggregate = Aggregate::new().count();
+    if filter.trim().is_empty() { aggregate } else { aggregate.filter(filter) }
+}
+
+fn single_row(mut rows: Vec<AggregateRow>) -> Result<AggregateRow, DeviiError> {
+    rows.pop().ok_or(DeviiError::MissingData { field: "Aggregates".to_string() })
+}
+
+#[derive(Serialize, Debug)]
+struct AggregateQuery {
+    query: String,
+    variables: Map<String, Value>
+}
+
+type AggregateOperation = Operation<DeviiQueryResult<Map<String, Value>>, Vec<AggregateRow>>;
+
+fn aggregate_operation<T: DeviiTrait>(aggregate: &Aggregate<T>) -> Result<AggregateOperation, DeviiError> {
+    if aggregate.functions.is_empty() {
+        return Err(DeviiError::InvalidInput("the aggregate computes no functions".to_string()));
+    }
+    let table = T::table_name();
+
+    let mut definitions = String::new();
+    let mut arguments = vec![];
+    let mut variables = Map::new();
+    if let Some(filter) = &aggregate.filter {
+        definitions = " ($filter: String)".to_string();
+        arguments.push("filter: $filter".to_string());
+        variables.insert("filter".to_string(), Value::from(filter.as_str()));
+    }
+    if !aggregate.group_by.is_empty() {
+        let subjects: Vec<Value> = aggregate.group_by.iter().map(|c| Value::from(format!("{}.{}", table, c))).collect();
+        arguments.push(format!("group_by: {}", Value::Array(subjects)));
+    }
+
+    // JSON strings are valid GraphQL literals.
+    let fields: Vec<String> = aggregate.functions.iter().enumerate().map(|(i, (function, column))| {
+        let subject = Value::from(format!("{}.{}", table, column));
+        let arguments = std::iter::once(format!("subject: {}", subject)).chain(arguments.iter().cloned()).collect::<Vec<_>>();
+        format!("a_{}: {}({})", i, function.name(), arguments.join(", "))
+    }).collect();
+
+    let query = AggregateQuery {
+        query: format!("query aggregate{}{{
+        Aggregates {{
+          {}
+        }}
+      }}", definitions, fields.join("\n          ")),
+        variables
+    };
+
+    let functions = aggregate.functions.clone();
+    let group_by = aggregate.group_by.clone();
+    Operation::new(&query, move |mut result: DeviiQueryResult<Map<String, Value>>| {
+        let mut values = result.take("Aggregates")?;
+        let mut rows: Vec<(Vec<Value>, AggregateRow)> = vec![];
+
+        for (i, (function, column)) in functions.into_iter().enumerate() {
+            let value = values.remove(&format!("a_{}", i)).unwrap_or(Value::Null);
+            let groups = if group_by.is_empty() {
+                vec![(vec![], value)]
+            } else {
+                read_groups(value)?
+            };
+
+            for (key, value) in groups {
+                let index = match rows.iter().position(|(k, _)| *k == key) {
+                    Some(index) => index,
+                    None => {
+                        let group = group_by.iter().cloned().zip(key.iter().cloned()).collect();
+                        rows.push((key, AggregateRow { group, values: vec![] }));
+                        rows.len() - 1
+                    }
+                };
+                rows[index].1.values.push((function, column.clone(), value));
+            }
+        }
+
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    })
+}
+
+// The `{ group, value }` entries of a grouped aggregate.
+fn read_groups(value: Value) -> Result<Vec<(Vec<Value>, Value)>, DeviiError> {
+    let entries = match value {
+        Value::Array(entries) => entries,
+        Value::Null => vec![],
+        other => return Err(DeviiError::MissingData { field: format!("groups in {}", other) })
+    };
+    entries.into_iter().map(|entry| match entry {
+        Value::Object(mut entry) => {
+            let group = match entry.remove("group") {
+                Some(Value::Array(group)) => group,
+                Some(value) => vec![value],
+                None => return Err(DeviiError::MissingData { field: "group".to_string() })
+            };
+            Ok((group, entry.remove("value").unwrap_or(Value::Null)))
+        },
+        other => Err(DeviiError::MissingData { field: format!("group in {}", other) })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregate::{Aggregate, AggregateFunction};
+    use crate::error::DeviiError;
+    use crate::filter::col;
+    use crate::test_struct::{connect, insert_rows, TestStruct};
+
+    #[test]
+    fn aggregate_test() {
+        let (server, client) = connect();
+        insert_rows(&server, "test_struct", [("a", 1), ("b", 2), ("a", 4)].map(|(c, n)| serde_json::json!({ "_char": c, "_u8": n })));
+
+        assert_eq!(client.count_sync::<TestStruct>("").unwrap(), 3);
+        assert_eq!(tokio_test::block_on(client.count::<TestStruct>(col("_u8").gt(1))).unwrap(), 2);
+        assert_eq!(client.count_sync::<TestStruct>("_u8 > 10").unwrap(), 0);
+
+        let totals = Aggregate::<TestStruct>::new().sum("_u8").avg("_u8").min("_char").max("_u8");
+        let row = client.aggregate_sync(&totals).unwrap().pop().unwrap();
+        assert_eq!(row.sum::<u64>("_u8").unwrap(), Some(7));
+        assert_eq!(row.avg::<f64>("_u8").unwrap(), Some(7.0 / 3.0));
+        assert_eq!(row.min::<char>("_char").unwrap(), Some('a'));
+        assert_eq!(row.get::<u8>(AggregateFunction::Max, "_u8").unwrap(), Some(4));
+        assert!(matches!(row.count(), Err(DeviiError::MissingData { .. })));
+
+        let grouped = Aggregate::<TestStruct>::new().count().sum("_u8").group_by("_char").filter("_u8 < 4");
+        let rows = tokio_test::block_on(client.aggregate(&grouped)).unwrap();
+        let groups: Vec<_> = rows.iter().map(|r| (r.group()["_char"].clone(), r.count().unwrap(), r.sum::<u64>("_u8").unwrap())).collect();
+        assert_eq!(groups, vec![(serde_json::json!("a"), 1, Some(1)), (serde_json::json!("b"), 1, Some(2))]);
+
+        let empty = Aggregate::<TestStruct>::new().sum("_u8").filter("_u8 > 10");
+        assert_eq!(client.aggregate_sync(&empty).unwrap()[0].sum::<u64>("_u8").unwrap(), None);
+        assert!(matches!(client.aggregate_sync(&Aggregate::<TestStruct>::new()), Err(DeviiError::InvalidInput(_))));
+    }
+}
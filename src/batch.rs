@@ -0,0 +1,494 @@
+//! Sending many mutations at once. Each request is one GraphQL document in which every mutation
+//! has its own alias, so Devii reports success or failure per mutation.
+//!
+//! Large batches are split into several requests so a single document never grows beyond what
+//! Devii accepts.
+
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::devii::{batch_insert_each_operation, insert_input, DeviiClient, DeviiQueryResult, DeviiTrait, Operation};
+use crate::error::{DeviiError, GraphQLError};
+use crate::id::{key_arguments, read_key};
+
+/// How `batch_insert_chunked` splits objects into requests.
+#[derive(Debug, Clone, Builder)]
+#[builder(default)]
+pub struct BatchOptions {
+    /// Maximum objects per request.
+    pub(crate) max_items: usize,
+    /// Maximum size in bytes of the inputs of one request. An object larger than this is sent on its own.
+    #[builder(setter(strip_option))]
+    pub(crate) max_bytes: Option<usize>,
+    /// How many requests the async method keeps in flight. The blocking method sends one at a time.
+    pub(crate) concurrency: usize
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            max_items: 100,
+            max_bytes: None,
+            concurrency: 1
+        }
+    }
+}
+
+/// Objects that weren't inserted, by their index in the input, and why.
+#[derive(Debug)]
+pub struct BatchFailure {
+    /// Every object of a request that failed as a whole shares the same failure.
+    pub indices: Vec<usize>,
+    pub error: DeviiError
+}
+
+/// The outcome of `batch_insert_chunked`, `I` being the key type of the inserted objects.
+#[derive(Debug)]
+pub struct BatchReport<I> {
+    /// Index in the input and id of every inserted object, in input order.
+    pub inserted: Vec<(usize, I)>,
+    pub failed: Vec<BatchFailure>
+}
+
+impl<I> Default for BatchReport<I> {
+    fn default() -> Self {
+        BatchReport { inserted: vec![], failed: vec![] }
+    }
+}
+
+impl<I> BatchReport<I> {
+    /// Whether every object was inserted.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// The indices of the objects that weren't inserted, in input order.
+    pub fn failed_indices(&self) -> Vec<usize> {
+        let mut indices: Vec<usize> = self.failed.iter().flat_map(|f| f.indices.iter().copied()).collect();
+        indices.sort_unstable();
+        indices
+    }
+
+    fn record(&mut self, chunk: Vec<usize>, result: Result<Vec<Result<I, DeviiError>>, DeviiError>) {
+        match result {
+            Ok(results) => {
+                for (index, result) in chunk.into_iter().zip(results) {
+                    match result {
+                        Ok(id) => self.inserted.push((index, id)),
+                        Err(error) => self.failed.push(BatchFailure { indices: vec![index], error })
+                    }
+                }
+            },
+            Err(error) => self.failed.push(BatchFailure { indices: chunk, error })
+        }
+    }
+}
+
+impl BatchOptions {
+    fn validate(&self) -> Result<(), DeviiError> {
+        if self.max_items == 0 || self.concurrency == 0 || self.max_bytes == Some(0) {
+            return Err(DeviiError::InvalidInput("max_items, max_bytes and concurrency must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+
+    // Groups the indices of `objects` into chunks that respect `max_items` and `max_bytes`.
+    fn chunks<T: DeviiTrait>(&self, objects: &[&T]) -> Vec<Vec<usize>> {
+        let mut chunks: Vec<Vec<usize>> = vec![];
+        let mut chunk_bytes = 0;
+
+        for (index, object) in objects.iter().enumerate() {
+            let bytes = match self.max_bytes {
+                Some(_) => object.graphql_inputs().to_string().len(),
+                None => 0
+            };
+            let fits = match chunks.last() {
+                Some(chunk) => chunk.len() < self.max_items && !matches!(self.max_bytes, Some(max) if chunk_bytes + bytes > max),
+                None => false
+            };
+
+            if fits {
+                chunks.last_mut().unwrap().push(index);
+                chunk_bytes += bytes;
+            } else {
+                chunks.push(vec![index]);
+                chunk_bytes = bytes;
+            }
+        }
+        chunks
+    }
+}
+
+impl DeviiClient {
+    /// Inserts `objects` in as many requests as `options` requires. A failing object or request
+    /// doesn't stop the others; the report lists what was inserted and what failed.
+    pub async fn batch_insert_chunked<T: DeviiTrait + Sync>(&self, objects: Vec<&T>, options: &BatchOptions) -> Result<BatchReport<T::Id>, DeviiError> {
+        options.validate()?;
+        let chunks = options.chunks(&objects);
+
+        let objects = &objects;
+        let results: Vec<_> = stream::iter(chunks)
+            .map(|chunk| async move {
+                let items: Vec<&T> = chunk.iter().map(|i| objects[*i]).collect();
+                let result = match batch_insert_each_operation(&items) {
+                    Ok(operation) => self.run(operation).await,
+                    Err(e) => Err(e)
+                };
+                (chunk, result)
+            })
+            .buffered(options.concurrency)
+            .collect()
+            .await;
+
+        let mut report = BatchReport::default();
+        for (chunk, result) in results {
+            report.record(chunk, result);
+        }
+        Ok(report)
+    }
+
+    pub fn batch_insert_chunked_sync<T: DeviiTrait>(&self, objects: Vec<&T>, options: &BatchOptions) -> Result<BatchReport<T::Id>, DeviiError> {
+        options.validate()?;
+
+        let mut report = BatchReport::default();
+        for chunk in options.chunks(&objects) {
+            let items: Vec<&T> = chunk.iter().map(|i| objects[*i]).collect();
+            let result = batch_insert_each_operation(&items).and_then(|operation| self.run_sync(operation));
+            report.record(chunk, result);
+        }
+        Ok(report)
+    }
+}
+
+/// Picks the result of one mutation out of `MutationResults`.
+#[derive(Debug, Clone)]
+pub struct MutationHandle<R> {
+    alias: String,
+    decode: fn(Value) -> Result<R, DeviiError>
+}
+
+/// Inserts, updates and deletes of any `DeviiTrait` types sent together as one aliased mutation.
+#[derive(Debug, Default)]
+pub struct MutationBatch {
+    definitions: Vec<String>,
+    fields: Vec<String>,
+    variables: Map<String, Value>
+}
+
+impl MutationBatch {
+    pub fn new() -> Self {
+        MutationBatch::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn push<R>(&mut self, field: String, decode: fn(Value) -> Result<R, DeviiError>) -> MutationHandle<R> {
+        let alias = format!("op_{}", self.fields.len());
+        self.fields.push(format!("{}: {}", alias, field));
+        MutationHandle { alias, decode }
+    }
+
+    /// Adds an insert of `object`, whose handle yields the new id. Unset columns are left out, as
+    /// in `DeviiClient::insert`.
+    pub fn insert<T: DeviiTrait>(&mut self, object: &T) -> Result<MutationHandle<T::Id>, DeviiError> {
+        let input = self.insert_variable(object)?;
+        Ok(self.push(object.insert_query(input), |value| {
+            let record: Map<String, Value> = decode_value(value)?;
+            read_key(&T::id_columns(), &record)
+        }))
+    }
+
+    /// Adds an insert of `object`, whose handle yields the inserted record.
+    pub(crate) fn insert_record<T: DeviiTrait>(&mut self, object: &T) -> Result<MutationHandle<T>, DeviiError> {
+        let input = self.insert_variable(object)?;
+        let field = format!("create_{} (input: ${}) {}", T::table_name(), input, T::fetch_fields());
+        Ok(self.push(field, decode_value::<T>))
+    }
+
+    // Declares the input of the next mutation, an insert of `object`, and returns its name.
+    fn insert_variable<T: DeviiTrait>(&mut self, object: &T) -> Result<String, DeviiError> {
+        let input = format!("input_{}", self.fields.len());
+        self.variables.insert(input.clone(), Value::Object(insert_input(object.graphql_inputs())?));
+        self.definitions.push(format!("${}: {}", input, object.input_type()));
+        Ok(input)
+    }
+
+    /// Adds an update of the record `id` to the values of `object`, whose handle yields the updated record.
+    pub fn update<T: DeviiTrait>(&mut self, object: &T, id: T::Id) -> MutationHandle<T> {
+        let n = self.fields.len();
+        self.definitions.push(format!("$input_{}: {}", n, object.input_type()));
+        self.variables.insert(format!("input_{}", n), object.graphql_inputs());
+
+        let key = key_arguments(&T::id_columns(), &id);
+        let field = format!("update_{} ({}, input: $input_{}) {}", T::table_name(), key, n, T::fetch_fields());
+        self.push(field, decode_value::<T>)
+    }
+
+    /// Adds a delete of `object`. Fails with `InvalidInput` if `object` has no key.
+    pub fn delete<T: DeviiTrait>(&mut self, object: &T) -> Result<MutationHandle<()>, DeviiError> {
+        let id = object.id().ok_or(DeviiError::InvalidInput(format!("delete on {} needs the key of the record", T::table_name())))?;
+        Ok(self.delete_by_id::<T>(&id))
+    }
+
+    /// Adds a delete of the record `id`.
+    pub fn delete_by_id<T: DeviiTrait>(&mut self, id: &T::Id) -> MutationHandle<()> {
+        let field = format!("delete_{} ({}){{ __typename }}", T::table_name(), key_arguments(&T::id_columns(), id));
+        self.push(field, |_| Ok(()))
+    }
+
+    /// Adds a delete of the record `id`, whose handle yields the deleted record.
+    pub fn delete_returning<T: DeviiTrait>(&mut self, id: &T::Id) -> MutationHandle<T> {
+        let field = format!("delete_{} ({}) {}", T::table_name(), key_arguments(&T::id_columns(), id), T::fetch_fields());
+        self.push(field, decode_value::<T>)
+    }
+
+    fn query(&self) -> MutationBatchQuery {
+        let definitions = if self.definitions.is_empty() {
+            "".to_string()
+        } else {
+            format!(" ({})", self.definitions.join(", "))
+        };
+        MutationBatchQuery {
+            query: format!("mutation batch{}{{\n        {}\n      }}", definitions, self.fields.join("\n        ")),
+            variables: self.variables.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct MutationBatchQuery {
+    query: String,
+    variables: Map<String, Value>
+}
+
+fn decode_value<R: DeserializeOwned>(value: Value) -> Result<R, DeviiError> {
+    serde_json::from_value(value.clone()).map_err(|source| DeviiError::Decode { body: value.to_string(), source })
+}
+
+/// The response to a `MutationBatch`. Each mutation succeeds or fails on its own.
+#[derive(Debug)]
+pub struct MutationResults {
+    result: DeviiQueryResult<Value>
+}
+
+impl MutationResults {
+    /// The result of the mutation behind `handle`, or the errors Devii reported for it.
+    pub fn take<R>(&mut self, handle: &MutationHandle<R>) -> Result<R, DeviiError> {
+        (handle.decode)(self.result.take(&handle.alias)?)
+    }
+
+    /// Every error of the batch.
+    pub fn errors(&self) -> &[GraphQLError] {
+        &self.result.errors
+    }
+}
+
+impl DeviiClient {
+    /// Sends every mutation of `batch` in one request.
+    pub async fn submit_batch(&self, batch: MutationBatch) -> Result<MutationResults, DeviiError> {
+        if batch.is_empty() {
+            return Ok(MutationResults { result: DeviiQueryResult { data: None, errors: vec![] } });
+        }
+        self.run(mutation_batch_operation(&batch)?).await
+    }
+
+    pub fn submit_batch_sync(&self, batch: MutationBatch) -> Result<MutationResults, DeviiError> {
+        if batch.is_empty() {
+            return Ok(MutationResults { result: DeviiQueryResult { data: None, errors: vec![] } });
+        }
+        self.run_sync(mutation_batch_operation(&batch)?)
+    }
+}
+
+fn mutation_batch_operation(batch: &MutationBatch) -> Result<Operation<DeviiQueryResult<Value>, MutationResults>, DeviiError> {
+    Operation::new(&batch.query(), |result: DeviiQueryResult<Value>| Ok(MutationResults { result }))
+}
+
+impl DeviiClient {
+    /// Updates every record `id` to the values of its object in one request. Returns, in the order
+    /// of `updates`, the updated record or why that update failed, like `batch_insert`.
+    pub async fn batch_update<T: DeviiTrait>(&self, updates: &[(T::Id, T)]) -> Result<Vec<Result<T, DeviiError>>, DeviiError> {
+        let (batch, handles) = update_batch(updates);
+        Ok(take_each(self.submit_batch(batch).await?, &handles))
+    }
+    pub fn batch_update_sync<T: DeviiTrait>(&self, updates: &[(T::Id, T)]) -> Result<Vec<Result<T, DeviiError>>, DeviiError> {
+        let (batch, handles) = update_batch(updates);
+        Ok(take_each(self.submit_batch_sync(batch)?, &handles))
+    }
+
+    /// Deletes every object in one request and returns whether each delete succeeded, in the
+    /// order of `objects`. Fails with `InvalidInput` before sending anything if an object has no key.
+    pub async fn batch_delete<T: DeviiTrait>(&self, objects: &[T]) -> Result<Vec<Result<(), DeviiError>>, DeviiError> {
+        let (batch, handles) = delete_batch(objects)?;
+        Ok(take_each(self.submit_batch(batch).await?, &handles))
+    }
+    pub fn batch_delete_sync<T: DeviiTrait>(&self, objects: &[T]) -> Result<Vec<Result<(), DeviiError>>, DeviiError> {
+        let (batch, handles) = delete_batch(objects)?;
+        Ok(take_each(self.submit_batch_sync(batch)?, &handles))
+    }
+}
+
+fn update_batch<T: DeviiTrait>(updates: &[(T::Id, T)]) -> (MutationBatch, Vec<MutationHandle<T>>) {
+    let mut batch = MutationBatch::new();
+    let handles = updates.iter().map(|(id, object)| batch.update(object, id.clone())).collect();
+    (batch, handles)
+}
+
+fn delete_batch<T: DeviiTrait>(objects: &[T]) -> Result<(MutationBatch, Vec<MutationHandle<()>>), DeviiError> {
+    let mut batch = MutationBatch::new();
+    let handles = objects.iter().map(|object| batch.delete(object)).collect::<Result<_, _>>()?;
+    Ok((batch, handles))
+}
+
+// The result of every mutation, a failed one doesn't hide those Devii committed with it.
+fn take_each<R>(mut results: MutationResults, handles: &[MutationHandle<R>]) -> Vec<Result<R, DeviiError>> {
+    handles.iter().map(|handle| results.take(handle)).collect()
+}
+
+// Fails with every error of the batch if any mutation failed. The mutations that succeeded are
+// committed all the same, only their results are dropped.
+pub(crate) fn take_all<R>(mut results: MutationResults, handles: &[MutationHandle<R>]) -> Result<Vec<R>, DeviiError> {
+    if !results.errors().is_empty() {
+        return Err(DeviiError::GraphQL(results.result.errors));
+    }
+    handles.iter().map(|handle| results.take(handle)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{BatchOptionsBuilder, MutationBatch};
+    use crate::error::DeviiError;
+    use crate::test_struct::{connect, insert_rows, TestManyToOne, TestOneToMany, TestStruct};
+
+    #[test]
+    fn batch_insert_returns_ids_test() {
+        let (server, client) = connect();
+
+        let mut parents = vec![TestOneToMany::new(), TestOneToMany::new(), TestOneToMany::new()];
+        for (i, parent) in parents.iter_mut().enumerate() {
+            parent.value = format!("parent {}", i);
+        }
+
+        let ids: Vec<u64> = client.batch_insert_sync(parents.iter().collect()).unwrap().into_iter().map(Result::unwrap).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // Unset columns, the id among them, are left out as in a single insert.
+        let variables: serde_json::Value = serde_json::from_str(server.queries()[0]["variables"].as_str().unwrap()).unwrap();
+        assert_eq!(variables["input_0"], serde_json::json!({ "value": "parent 0" }));
+
+        let rows = server.rows("test_one_to_many");
+        for (id, parent) in ids.iter().zip(&parents) {
+            let row = rows.iter().find(|r| r["id"] == *id).unwrap();
+            assert_eq!(row["value"], parent.value);
+        }
+
+        let ids = tokio_test::block_on(client.batch_insert(Vec::<&TestOneToMany>::new())).unwrap();
+        assert!(ids.is_empty());
+
+        // A failed object doesn't hide the ids of those inserted with it.
+        let taken = TestOneToMany { id: Some(2), ..TestOneToMany::new() };
+        let fresh = TestOneToMany { value: "fresh".to_string(), ..TestOneToMany::new() };
+        let results = tokio_test::block_on(client.batch_insert(vec![&taken, &fresh])).unwrap();
+        assert!(matches!(&results[0], Err(DeviiError::GraphQL(e)) if e[0].field() == Some("insert_0")));
+        assert_eq!(results[1].as_ref().unwrap(), &4);
+    }
+
+    #[test]
+    fn batch_insert_chunked_test() {
+        let (server, client) = connect();
+        server.insert_row("test_many_to_one", serde_json::json!({ "id": 2, "value": "taken" }));
+
+        // The third object reuses an existing id and fails on its own.
+        let mut children: Vec<TestManyToOne> = (0..5).map(|i| TestManyToOne { value: format!("child {}", i), ..Default::default() }).collect();
+        children[2].id = Some(2);
+
+        let options = BatchOptionsBuilder::default().max_items(2).build().unwrap();
+        let report = client.batch_insert_chunked_sync(children.iter().collect(), &options).unwrap();
+
+        assert_eq!(server.queries().len(), 3);
+        assert_eq!(report.inserted.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1, 3, 4]);
+        assert_eq!(report.failed_indices(), vec![2]);
+        assert!(matches!(report.failed[0].error, DeviiError::GraphQL(_)));
+
+        // Every object is bigger than `max_bytes` so each one gets its own request.
+        let options = BatchOptionsBuilder::default().max_bytes(10).concurrency(2).build().unwrap();
+        let fresh: Vec<TestManyToOne> = (0..3).map(|_| TestManyToOne::default()).collect();
+        let report = tokio_test::block_on(client.batch_insert_chunked(fresh.iter().collect(), &options)).unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(report.inserted.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(server.queries().len(), 6);
+    }
+
+    #[test]
+    fn mutation_batch_test() {
+        let (server, client) = connect();
+        let existing = server.insert_row("test_struct", serde_json::to_value(TestStruct::new()).unwrap()).as_u64().unwrap();
+        let child_id = server.insert_row("test_many_to_one", serde_json::json!({ "value": "child" })).as_u64().unwrap();
+
+        let mut changed = TestStruct::new();
+        changed.string = "changed".to_string();
+        let child = TestManyToOne { id: Some(child_id), ..Default::default() };
+
+        let mut batch = MutationBatch::new();
+        let parent = batch.insert(&TestOneToMany::new()).unwrap();
+        let record = batch.insert(&TestStruct::new_min()).unwrap();
+        let update = batch.update(&changed, existing);
+        let missing = batch.update(&changed, 404);
+        let delete = batch.delete(&child).unwrap();
+        assert!(matches!(batch.delete(&TestManyToOne::default()), Err(DeviiError::InvalidInput(_))));
+
+        let mut results = tokio_test::block_on(client.submit_batch(batch)).unwrap();
+        assert_eq!(server.queries().len(), 1);
+        assert_eq!(server.queries()[0]["variables"]["input_0"], serde_json::json!({ "value": "OneToMany" }));
+
+        assert_eq!(results.take(&parent).unwrap(), 1);
+        assert_eq!(results.take(&record).unwrap(), 2);
+        assert_eq!(results.take(&update).unwrap().string, "changed");
+        assert!(matches!(results.take(&missing), Err(DeviiError::NotFound)));
+        results.take(&delete).unwrap();
+        assert!(server.rows("test_many_to_one").is_empty());
+
+        let results = client.submit_batch_sync(MutationBatch::new()).unwrap();
+        assert!(results.errors().is_empty());
+        assert_eq!(server.queries().len(), 1);
+    }
+
+    #[test]
+    fn batch_update_delete_test() {
+        let (server, client) = connect();
+        let ids = insert_rows(&server, "test_struct", (0..3).map(|_| serde_json::to_value(TestStruct::new()).unwrap()));
+
+        let updates: Vec<(u64, TestStruct)> = ids[..2].iter().map(|id| {
+            let mut record = TestStruct::new();
+            record.string = format!("updated {}", id);
+            (*id, record)
+        }).collect();
+        let updated = tokio_test::block_on(client.batch_update(&updates)).unwrap();
+        assert_eq!(updated.iter().map(|r| r.as_ref().unwrap().string.as_str()).collect::<Vec<_>>(), vec!["updated 1", "updated 2"]);
+        assert_eq!(server.rows("test_struct")[1]["string"], "updated 2");
+
+        // A missing record doesn't hide the update committed next to it.
+        let mut results = client.batch_update_sync(&[(404, TestStruct::new()), (ids[0], TestStruct { string: "updated 1".to_string(), ..TestStruct::new() })]).unwrap();
+        assert!(matches!(results.remove(0), Err(DeviiError::NotFound)));
+        assert_eq!(results.remove(0).unwrap().string, "updated 1");
+
+        let deleted: Vec<TestStruct> = ids[1..].iter().map(|id| TestStruct { id: Some(*id), ..Default::default() }).collect();
+        assert!(client.batch_delete_sync(&deleted).unwrap().iter().all(Result::is_ok));
+        assert!(tokio_test::block_on(client.batch_delete::<TestStruct>(&[])).unwrap().is_empty());
+
+        // An object without a key is rejected before anything is sent.
+        assert!(matches!(client.batch_delete_sync(&[TestStruct::new()]), Err(DeviiError::InvalidInput(_))));
+        assert_eq!(server.rows("test_struct").len(), 1);
+        assert_eq!(server.queries().len(), 3);
+        assert!(!server.queries()[2]["query"].as_str().unwrap().contains("null"));
+    }
+}
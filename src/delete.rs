@@ -0,0 +1,182 @@
+//! Deleting by key or by filter. Devii only deletes one record per mutation, by its key, so
+//! `delete_where` first looks up the keys of the matching records and then deletes them in
+//! `MutationBatch`es of at most `BatchOptions::default().max_items` deletes each.
+//!
+//! The lookup and the deletes are separate requests: a record that starts matching in between is
+//! left alone, and one deleted by someone else in between is left out of the result. The batches
+//! are sent one after the other and the first failing one stops the deletion, so the records of
+//! the batches before it stay deleted.
+//!
+//! The `_returning` variants select `T::fetch_fields()` on each delete, so the deleted records come
+//! back in full, e.g. to log or undo the deletion.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::batch::{BatchOptions, MutationBatch, MutationHandle, MutationResults};
+use crate::devii::{DeviiClient, DeviiQueryResult, DeviiTrait, Operation};
+use crate::error::DeviiError;
+use crate::id::read_key;
+
+impl DeviiClient {
+    /// Deletes the record `id`. Fails with `NotFound` if there is none.
+    pub async fn delete_by_id<T: DeviiTrait>(&self, id: &T::Id) -> Result<(), DeviiError> {
+        let mut batch = MutationBatch::new();
+        let handle = batch.delete_by_id::<T>(id);
+        self.submit_batch(batch).await?.take(&handle)
+    }
+
+    pub fn delete_by_id_sync<T: DeviiTrait>(&self, id: &T::Id) -> Result<(), DeviiError> {
+        let mut batch = MutationBatch::new();
+        let handle = batch.delete_by_id::<T>(id);
+        self.submit_batch_sync(batch)?.take(&handle)
+    }
+
+    /// Deletes the record `id` and returns it as it was. Fails with `NotFound` if there is none.
+    pub async fn delete_by_id_returning<T: DeviiTrait>(&self, id: &T::Id) -> Result<T, DeviiError> {
+        let mut batch = MutationBatch::new();
+        let handle = batch.delete_returning::<T>(id);
+        self.submit_batch(batch).await?.take(&handle)
+    }
+
+    pub fn delete_by_id_returning_sync<T: DeviiTrait>(&self, id: &T::Id) -> Result<T, DeviiError> {
+        let mut batch = MutationBatch::new();
+        let handle = batch.delete_returning::<T>(id);
+        self.submit_batch_sync(batch)?.take(&handle)
+    }
+
+    /// Deletes every record matching `filter`, a filter string or a `devii::filter::Filter`, and
+    /// returns how many were deleted.
+    ///
+    /// A blank filter is rejected with `InvalidInput` rather than deleting the whole table. That is
+    /// the only check: a filter every record matches, e.g. `"id > 0"`, deletes them all.
+    pub async fn delete_where<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<usize, DeviiError> {
+        let ids = self.run(keys_operation::<T>(filter.into())?).await?;
+        let mut deleted = 0;
+        for (batch, handles) in delete_batches::<T, _>(&ids, MutationBatch::delete_by_id::<T>) {
+            deleted += take_deleted(self.submit_batch(batch).await?, &handles)?.len();
+        }
+        Ok(deleted)
+    }
+
+    pub fn delete_where_sync<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<usize, DeviiError> {
+        let ids = self.run_sync(keys_operation::<T>(filter.into())?)?;
+        let mut deleted = 0;
+        for (batch, handles) in delete_batches::<T, _>(&ids, MutationBatch::delete_by_id::<T>) {
+            deleted += take_deleted(self.submit_batch_sync(batch)?, &handles)?.len();
+        }
+        Ok(deleted)
+    }
+
+    /// `delete_where` returning the deleted records as they were.
+    pub async fn delete_where_returning<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<Vec<T>, DeviiError> {
+        let ids = self.run(keys_operation::<T>(filter.into())?).await?;
+        let mut deleted = vec![];
+        for (batch, handles) in delete_batches::<T, _>(&ids, MutationBatch::delete_returning::<T>) {
+            deleted.extend(take_deleted(self.submit_batch(batch).await?, &handles)?);
+        }
+        Ok(deleted)
+    }
+
+    pub fn delete_where_returning_sync<T: DeviiTrait>(&self, filter: impl Into<String>) -> Result<Vec<T>, DeviiError> {
+        let ids = self.run_sync(keys_operation::<T>(filter.into())?)?;
+        let mut deleted = vec![];
+        for (batch, handles) in delete_batches::<T, _>(&ids, MutationBatch::delete_returning::<T>) {
+            deleted.extend(take_deleted(self.submit_batch_sync(batch)?, &handles)?);
+        }
+        Ok(deleted)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct KeysQuery {
+    query: String,
+    variables: KeysVariables
+}
+
+#[derive(Serialize, Debug)]
+struct KeysVariables {
+    filter: String
+}
+
+type KeysOperation<I> = Operation<DeviiQueryResult<Vec<Map<String, Value>>>, Vec<I>>;
+
+// The keys of every record matching `filter`.
+fn keys_operation<T: DeviiTrait>(filter: String) -> Result<KeysOperation<T::Id>, DeviiError> {
+    if filter.trim().is_empty() {
+        return Err(DeviiError::InvalidInput(format!("delete_where on {} needs a filter", T::table_name())));
+    }
+    let table = T::table_name();
+    let columns = T::id_columns();
+
+    let query = KeysQuery {
+        query: format!("query keys ($filter: String){{
+        {} (filter: $filter){{ {} }}
+      }}", table, columns.join(" ")),
+        variables: KeysVariables { filter }
+    };
+
+    Operation::new(&query, move |mut result: DeviiQueryResult<Vec<Map<String, Value>>>| {
+        result.take(&table)?.iter().map(|row| read_key(&columns, row)).collect()
+    })
+}
+
+type DeleteBatch<R> = (MutationBatch, Vec<MutationHandle<R>>);
+
+// One batch per `max_items` ids, with the handle of each delete.
+fn delete_batches<T: DeviiTrait, R>(ids: &[T::Id], delete: fn(&mut MutationBatch, &T::Id) -> MutationHandle<R>) -> Vec<DeleteBatch<R>> {
+    ids.chunks(BatchOptions::default().max_items).map(|chunk| {
+        let mut batch = MutationBatch::new();
+        let handles = chunk.iter().map(|id| delete(&mut batch, id)).collect();
+        (batch, handles)
+    }).collect()
+}
+
+// The result of every delete, leaving out records that were already gone.
+fn take_deleted<R>(mut results: MutationResults, handles: &[MutationHandle<R>]) -> Result<Vec<R>, DeviiError> {
+    if !results.errors().is_empty() {
+        return Err(DeviiError::GraphQL(results.errors().to_vec()));
+    }
+    let mut deleted = vec![];
+    for handle in handles {
+        match results.take(handle) {
+            Ok(record) => deleted.push(record),
+            Err(DeviiError::NotFound) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviiError;
+    use crate::filter::col;
+    use crate::test_struct::{connect, insert_rows, TestManyToOne};
+
+    #[test]
+    fn delete_by_id_and_filter_test() {
+        let (server, client) = connect();
+        insert_rows(&server, "test_many_to_one", ["a", "b", "c", "d"].map(|value| serde_json::json!({ "value": value })));
+
+        client.delete_by_id_sync::<TestManyToOne>(&1).unwrap();
+        let deleted: TestManyToOne = tokio_test::block_on(client.delete_by_id_returning(&2)).unwrap();
+        assert_eq!((deleted.id, deleted.value.as_str()), (Some(2), "b"));
+        assert!(matches!(client.delete_by_id_sync::<TestManyToOne>(&1), Err(DeviiError::NotFound)));
+
+        assert!(matches!(client.delete_where_sync::<TestManyToOne>(" "), Err(DeviiError::InvalidInput(_))));
+        assert_eq!(client.delete_where_sync::<TestManyToOne>(col("value").eq("nothing")).unwrap(), 0);
+
+        let deleted: Vec<TestManyToOne> = client.delete_where_returning_sync(col("value").eq("c")).unwrap();
+        assert_eq!(deleted.iter().map(|r| r.id).collect::<Vec<_>>(), vec![Some(3)]);
+        assert_eq!(tokio_test::block_on(client.delete_where::<TestManyToOne>("id > 0")).unwrap(), 1);
+        assert!(server.rows("test_many_to_one").is_empty());
+
+        // Large deletions are split into batches of `max_items` deletes.
+        insert_rows(&server, "test_many_to_one", (0..150).map(|_| serde_json::json!({ "value": "bulk" })));
+        let queries = server.queries().len();
+        assert_eq!(client.delete_where_sync::<TestManyToOne>(col("value").eq("bulk")).unwrap(), 150);
+        assert_eq!(server.queries().len() - queries, 3);
+        assert!(server.rows("test_many_to_one").is_empty());
+    }
+}
@@ -3,42 +3,197 @@
 // What happens when I want to serialize to JSON to return to web? 
 // serialize if: 
 
-use reqwest;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use named_type::NamedType;
 use convert_case::{Case, Casing};
 use core::fmt::Debug;
+use std::sync::{Arc, RwLock};
 use serde_json::{Map, Value};
-use easy_error::bail;
+use futures::lock::Mutex;
+use futures::stream::{self, Stream};
+use crate::error::{DeviiError, GraphQLError};
+use crate::id::{key_arguments, key_filter, read_key, DeviiId};
+pub use crate::transport::{HttpOptions, HttpOptionsBuilder};
+use crate::transport::{BlockingReqwestTransport, BlockingTransport, HttpRequest, ReqwestTransport, Transport};
 
 
 pub trait GraphQLQuery{}
 
 pub trait DeviiTrait: NamedType + Debug + DeserializeOwned + Serialize{
+    /// The type of the primary key, e.g. `u64`, `String` or a tuple for composite keys.
+    type Id: DeviiId;
     fn insert_query(&self, param: String) -> String;
     fn input_type(&self) -> String; 
     fn graphql_inputs(&self) -> Value;
     fn fetch_fields() -> String where Self: Sized;
+    /// Selection of only the struct's own columns, used when the struct is selected
+    /// through a relation. Defaults to `fetch_fields`.
+    fn scalar_fields() -> String where Self: Sized {
+        Self::fetch_fields()
+    }
+    /// Name of the Devii table. Defaults to the snake cased type name.
+    fn table_name() -> String where Self: Sized {
+        Self::short_type_name().to_case(Case::Snake)
+    }
+    /// How each field maps onto the table, see `FieldInfo`.
+    fn fields() -> Vec<FieldInfo> where Self: Sized {
+        vec![]
+    }
+    /// The primary key columns, in the order of `Id`. Defaults to `id`.
+    fn id_columns() -> Vec<&'static str> where Self: Sized {
+        vec!["id"]
+    }
+    /// The primary key, `None` until the record is inserted.
+    fn id(&self) -> Option<Self::Id>;
     /// Example: 
     /// id: 7 
     /// hash: "hashy", index: 8
     fn delete_input(&self) -> String;
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// What a struct field represents in its Devii table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    /// A column that is selected and sent as input.
+    Column,
+    /// A column that is selected but never sent as input, e.g. a serial id.
+    ReadOnly,
+    /// The one side of a one to many relation, e.g. `test_many_to_one_collection`. The
+    /// children point back through their `fk` column.
+    HasMany { fk: &'static str },
+    /// The many side of a one to many relation, linked through the `fk` column.
+    BelongsTo { fk: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldInfo {
+    /// The serde name of the field.
+    pub name: &'static str,
+    /// The name of the column or relation in Devii.
+    pub graphql_name: &'static str,
+    pub kind: FieldKind,
+    /// The field's Rust type without `Option`, e.g. `u64` or `Vec<String>`.
+    pub rust_type: &'static str,
+    /// Whether the field is an `Option`.
+    pub optional: bool,
+}
+
+/// A connection to a Devii tenant. Clones share the same session, so a token refreshed by one
+/// clone is used by all of them.
+#[derive(Debug, Clone)]
 pub struct DeviiClient {
+    session: Arc<RwLock<DeviiSession>>,
+    message: String,
+    routes: DeviiRoutes,
+    transport: Arc<dyn Transport>,
+    blocking_transport: Arc<dyn BlockingTransport>,
+    // Held while refreshing an expired token, so clones that hit the expiry together refresh once.
+    refresh_lock: Arc<Mutex<()>>
+}
+
+#[derive(Debug)]
+struct DeviiSession {
+    access_token: String,
+    refresh_token: String
+}
+
+// Body returned by `POST /auth`.
+#[derive(Serialize, Deserialize, Debug)]
+struct DeviiAuthResponse {
     access_token: String,
     refresh_token: String,
     message: String,
     routes: DeviiRoutes
 }
 
+// Body returned by `GET /auth` when refreshing with the refresh token.
+#[derive(Serialize, Deserialize, Debug)]
+struct DeviiRefreshResponse {
+    access_token: String,
+    refresh_token: Option<String>
+}
+
+// A query together with how its result is read. Every operation is built once and then sent by
+// either `DeviiClient::run` or `DeviiClient::run_sync`, so both flavours behave the same.
+pub(crate) struct Operation<D, R> {
+    body: Value,
+    decode: Box<dyn FnOnce(D) -> Result<R, DeviiError> + Send>
+}
+
+impl<D: DeserializeOwned, R> Operation<D, R> {
+    pub(crate) fn new<K: Serialize>(query: &K, decode: impl FnOnce(D) -> Result<R, DeviiError> + Send + 'static) -> Result<Self, DeviiError> {
+        Ok(Operation {
+            body: serde_json::to_value(query)?,
+            decode: Box::new(decode)
+        })
+    }
+
+    // Post-processes the decoded result.
+    pub(crate) fn map<S>(self, f: impl FnOnce(R) -> Result<S, DeviiError> + Send + 'static) -> Operation<D, S> where D: 'static, R: 'static {
+        let decode = self.decode;
+        Operation {
+            body: self.body,
+            decode: Box::new(move |data| f(decode(data)?))
+        }
+    }
+}
+
 impl DeviiClient {
-    fn set_access_token(&mut self, token: String) -> &mut Self {
-        self.access_token = token;
-        self
+    fn new(auth: DeviiAuthResponse, transport: Arc<dyn Transport>, blocking_transport: Arc<dyn BlockingTransport>) -> Self {
+        DeviiClient {
+            session: Arc::new(RwLock::new(DeviiSession {
+                access_token: auth.access_token,
+                refresh_token: auth.refresh_token
+            })),
+            message: auth.message,
+            routes: auth.routes,
+            transport,
+            blocking_transport,
+            refresh_lock: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// The message Devii returned when authenticating.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn access_token(&self) -> String {
+        self.session.read().unwrap().access_token.clone()
+    }
+
+    fn refresh_request(&self) -> HttpRequest {
+        let refresh_token = self.session.read().unwrap().refresh_token.clone();
+        HttpRequest::get(format!("{}/auth", self.routes.base), Some(refresh_token))
+    }
+
+    fn query_request(&self, body: &Value, access_token: &str) -> HttpRequest {
+        HttpRequest::post(self.routes.query.clone(), Some(access_token.to_string()), body.clone())
+    }
+
+    fn update_session(&self, refreshed: DeviiRefreshResponse) {
+        let mut session = self.session.write().unwrap();
+        session.access_token = refreshed.access_token;
+        if let Some(refresh_token) = refreshed.refresh_token {
+            session.refresh_token = refresh_token;
+        }
+    }
+
+    /// Exchanges the refresh token for a new access token.
+    pub async fn refresh(&self) -> Result<(), DeviiError> {
+        let res = self.transport.send(self.refresh_request()).await?;
+
+        self.update_session(decode_response(res.status, res.body)?);
+        Ok(())
+    }
+
+    pub fn refresh_sync(&self) -> Result<(), DeviiError> {
+        let res = self.blocking_transport.send(self.refresh_request())?;
+
+        self.update_session(decode_response(res.status, res.body)?);
+        Ok(())
     }
 }
 
@@ -57,7 +212,14 @@ pub struct DeviiClientOptions {
     password: String,
 
     #[serde(skip_serializing)]
-    base: String
+    base: String,
+
+    #[serde(skip)]
+    http: HttpOptions,
+    #[serde(skip)]
+    transport: Option<Arc<dyn Transport>>,
+    #[serde(skip)]
+    blocking_transport: Option<Arc<dyn BlockingTransport>>
 }
 
 impl DeviiClientOptions {
@@ -66,27 +228,99 @@ impl DeviiClientOptions {
             login,
             tenantid,
             password,
-            base
+            base,
+            http: HttpOptions::default(),
+            transport: None,
+            blocking_transport: None
         }
     }
+
+    /// Configures the HTTP connection pool of the default reqwest transports.
+    pub fn http(mut self, http: HttpOptions) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Sends the requests of the async methods through `transport` instead of reqwest.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Sends the requests of the `_sync` methods through `transport` instead of reqwest.
+    pub fn blocking_transport(mut self, transport: Arc<dyn BlockingTransport>) -> Self {
+        self.blocking_transport = Some(transport);
+        self
+    }
+
+    fn transports(&self) -> Result<Transports, DeviiError> {
+        let transport: Arc<dyn Transport> = match &self.transport {
+            Some(t) => t.clone(),
+            None => Arc::new(ReqwestTransport::new(&self.http)?)
+        };
+        let blocking_transport: Arc<dyn BlockingTransport> = match &self.blocking_transport {
+            Some(t) => t.clone(),
+            None => Arc::new(BlockingReqwestTransport::new(&self.http))
+        };
+        Ok((transport, blocking_transport))
+    }
+
+    fn auth_request(&self) -> Result<HttpRequest, DeviiError> {
+        Ok(HttpRequest::post(format!("{}/auth", self.base), None, serde_json::to_value(self)?))
+    }
 }
 
+// The async and blocking transports a client sends its requests through.
+type Transports = (Arc<dyn Transport>, Arc<dyn BlockingTransport>);
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviiQueryOptions {
     pub query: String,
     pub variables: Option<FetchOptions>
 }
 
-#[derive(Serialize, Deserialize, Debug, Builder, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Builder, Default)]
 #[builder(setter(strip_option))]
 #[builder(default)]
 pub struct FetchOptions  {
+    /// A filter string or a `devii::filter::Filter`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option))]
     filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     offset: Option<u64>,
+    /// Columns to sort by, each optionally followed by `asc` or `desc`, e.g. `"id desc"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
     ordering: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     limit: Option<u64>
 }
 
+impl FetchOptions {
+    // The variable definitions and arguments for the options that are set, e.g.
+    // `($limit: Int)` and `(limit: $limit)`. Both are empty when nothing is set.
+    fn declarations(&self) -> (String, String) {
+        let mut definitions = vec![];
+        let mut arguments = vec![];
+
+        let variables = [
+            ("filter", "String", self.filter.is_some()),
+            ("offset", "Int", self.offset.is_some()),
+            ("ordering", "[String]", self.ordering.is_some()),
+            ("limit", "Int", self.limit.is_some())
+        ];
+        for (name, graphql_type, _) in variables.iter().filter(|(_, _, set)| *set) {
+            definitions.push(format!("${}: {}", name, graphql_type));
+            arguments.push(format!("{}: ${}", name, name));
+        }
+
+        if definitions.is_empty() {
+            return ("".to_string(), "".to_string());
+        }
+        (format!("({})", definitions.join(", ")), format!("({})", arguments.join(", ")))
+    }
+}
+
 impl GraphQLQuery for DeviiQueryOptions{}
 
 #[derive(Serialize, Debug, Deserialize)]
@@ -129,372 +363,594 @@ pub struct Insert<T: Serialize> {
 pub struct Update<T: Serialize> {
     // Docs: https://serde.rs/attr-bound.html
     #[serde(bound(deserialize = "T: Deserialize<'de>"))]
-    input: T,
-    id: u64 
+    input: T
 }
 
 impl <T: DeserializeOwned + Serialize>GraphQLQuery for DeviiQueryInsertOptions<T>{}
 
 
 impl DeviiClient {
-    pub async fn connect(options: DeviiClientOptions) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = reqwest::Client::new();
-
-        println!("{:?}", client.post(format!("{}/auth", options.base))
-        .json(&options)
-        .send()
-        .await?);
+    pub async fn connect(options: DeviiClientOptions) -> Result<Self, DeviiError> {
+        let (transport, blocking_transport) = options.transports()?;
 
-        let res = client.post(format!("{}/auth", options.base))
-            .json(&options)
-            .send()
-            .await?
-            .json::<DeviiClient>()
-            .await?;
+        let res = transport.send(options.auth_request()?).await?;
 
-        Ok(res)
+        let auth: DeviiAuthResponse = decode_response(res.status, res.body)?;
+        Ok(DeviiClient::new(auth, transport, blocking_transport))
     }
 
-    pub fn connect_sync(options: DeviiClientOptions) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = reqwest::blocking::Client::new();
+    pub fn connect_sync(options: DeviiClientOptions) -> Result<Self, DeviiError> {
+        let (transport, blocking_transport) = options.transports()?;
 
-        let res = client.post(format!("{}/auth", options.base))
-            .json(&options)
-            .send()?
-            .json::<DeviiClient>()?;
+        let res = blocking_transport.send(options.auth_request()?)?;
 
-        Ok(res)
+        let auth: DeviiAuthResponse = decode_response(res.status, res.body)?;
+        Ok(DeviiClient::new(auth, transport, blocking_transport))
     }
 
     // Type T has to be DeserializedOwned as required by .json<> when deserializing the result into a Rust Struct
-    pub async fn query<T: DeserializeOwned, K : GraphQLQuery + Serialize>(&self, options: &K) -> Result<T, Box< dyn std::error::Error>>
+    pub async fn query<T: DeserializeOwned, K : GraphQLQuery + Serialize>(&self, options: &K) -> Result<T, DeviiError>
     {
-        let client = reqwest::Client::new();
-        //Add Auth header
-        let res = client.post(&self.routes.query)
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .json(&options)
-            .build()?;
-
-        let execute_result = client.execute(res)
-        .await?;
-
-        let result_text = execute_result.text().await?;
-        // let result_text_clone = result_text.clone();
-        
-        let result = serde_json::from_str(&result_text);    
-            // .json::<T>()
-            // .await;
-        
-        match result {
-            Ok(r) => return Ok(r),
-            Err(e) => {
-                if "{\"error\":\"Token expired.\",\"status\":401}".to_string() == result_text.trim() {
-                    bail!("Query Failed: {:?}", e);
-                }
-                bail!("Failed to Parse struct from Result: {:?}, Error: {:?}", result_text, e)
-            }
-        }
+        self.send_query(&serde_json::to_value(options)?).await
     }
-    pub fn query_sync<T: DeserializeOwned, K : GraphQLQuery + Serialize>(&self, options: &K) -> Result<T, Box< dyn std::error::Error>>
+    pub fn query_sync<T: DeserializeOwned, K : GraphQLQuery + Serialize>(&self, options: &K) -> Result<T, DeviiError>
     {
-        let client = reqwest::blocking::Client::new();
-        //Add Auth header
-        let res = client.post(&self.routes.query)
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .json(&options)
-            .build()?;
+        self.send_query_sync(&serde_json::to_value(options)?)
+    }
 
-        let execute_result = client.execute(res)?;
+    // An expired access token is refreshed and the query retried once.
+    async fn send_query<T: DeserializeOwned>(&self, body: &Value) -> Result<T, DeviiError> {
+        let access_token = self.access_token();
 
-        let result_text = execute_result.text()?.to_string();
-        // let result_text_clone = result_text.clone();
-        
-        let result = serde_json::from_str(&result_text);    
-            // .json::<T>()
-            // .await;
-        
-        match result {
-            Ok(r) => return Ok(r),
-            Err(e) => bail!("Failed to Parse struct from Result: {:?}, Error: {:?}", result_text, e)
-        }
-    }
-    // returns UniqueIdentifier as string, string. 
-    pub async fn insert<T: DeserializeOwned + Serialize + NamedType>(&self, object: &T) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        // create query. 
-        let insert_object; 
-
-        if let Value::Object(mut map) = serde_json::to_value(object)? {
-            let mut keys  = map.keys();
-            let mut keys_to_remove = vec![];
-            while let Some(key) = keys.next() {
-                if let Some(value) = map.get(key) {
-                    match value {
-                        Value::Null => keys_to_remove.push(key.clone()),
-                        Value::Object(_) => keys_to_remove.push(key.clone()),
-                        Value::Array(_) => keys_to_remove.push(key.clone()),
-                        _ => continue,
-                    };
-                };
-            };
-            
-            while let Some(key) = keys_to_remove.pop() {
-                map.remove(&key);
-            }
+        let res = self.transport.send(self.query_request(body, &access_token)).await?;
 
-            insert_object = map
-        } else {
-            bail!("Struct not evaluated as an Object!")
+        match decode_response(res.status, res.body) {
+            Err(DeviiError::TokenExpired) => {
+                self.refresh_expired(&access_token).await?;
+                let res = self.transport.send(self.query_request(body, &self.access_token())).await?;
+                decode_response(res.status, res.body)
+            },
+            result => result
         }
+    }
+    fn send_query_sync<T: DeserializeOwned>(&self, body: &Value) -> Result<T, DeviiError> {
+        let access_token = self.access_token();
+
+        let res = self.blocking_transport.send(self.query_request(body, &access_token))?;
+
+        match decode_response(res.status, res.body) {
+            Err(DeviiError::TokenExpired) => {
+                self.refresh_expired_sync(&access_token)?;
+                let res = self.blocking_transport.send(self.query_request(body, &self.access_token()))?;
+                decode_response(res.status, res.body)
+            },
+            result => result
+        }
+    }
 
+    // Refreshes the session after `expired` was rejected, unless another clone already replaced
+    // it while this one waited for the lock.
+    async fn refresh_expired(&self, expired: &str) -> Result<(), DeviiError> {
+        let _refreshing = self.refresh_lock.lock().await;
+        if self.access_token() == expired {
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+    fn refresh_expired_sync(&self, expired: &str) -> Result<(), DeviiError> {
+        let _refreshing = futures::executor::block_on(self.refresh_lock.lock());
+        if self.access_token() == expired {
+            self.refresh_sync()?;
+        }
+        Ok(())
+    }
 
-        let insert = Insert {
-            input : insert_object
-        };
-
-        let snake_type = T::short_type_name().to_case(Case::Snake);
+    pub(crate) async fn run<D: DeserializeOwned, R>(&self, operation: Operation<D, R>) -> Result<R, DeviiError> {
+        let data = self.send_query(&operation.body).await?;
+        (operation.decode)(data)
+    }
+    pub(crate) fn run_sync<D: DeserializeOwned, R>(&self, operation: Operation<D, R>) -> Result<R, DeviiError> {
+        let data = self.send_query_sync(&operation.body)?;
+        (operation.decode)(data)
+    }
 
-        let query_string = format!("mutation insert ($input: {}Input){{
-            create_{} (input: $input){{
-              id
-            }}
-          }}",
-          snake_type,
-          snake_type
-        );
+    /// Inserts `object` and returns its primary key.
+    pub async fn insert<T: DeserializeOwned + Serialize + NamedType + DeviiTrait>(&self, object: &T) -> Result<T::Id, DeviiError> {
+        self.run(insert_operation(object)?).await
+    }
+    pub fn insert_sync<T: DeserializeOwned + Serialize + NamedType + DeviiTrait>(&self, object: &T) -> Result<T::Id, DeviiError> {
+        self.run_sync(insert_operation(object)?)
+    }
 
-        let query = DeviiQueryInsertOptions{ 
-            query: query_string,
-            variables: insert
-        };
+    /// Inserts `object` and returns the new record, with server side defaults such as serial ids
+    /// and timestamps filled in.
+    pub async fn insert_returning<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + 'static>(&self, object: &T) -> Result<T, DeviiError> {
+        self.run(insert_selecting_operation(object, &T::fetch_fields())?).await
+    }
+    pub fn insert_returning_sync<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + 'static>(&self, object: &T) -> Result<T, DeviiError> {
+        self.run_sync(insert_selecting_operation(object, &T::fetch_fields())?)
+    }
 
-        let mut result = self.query::<DeviiQueryResult<HashMap<String, String>>, DeviiQueryInsertOptions<serde_json::map::Map<String, Value>>>(&query).await?;
+    /// Inserts `object` and decodes the `selection` of the new record, e.g. `"{ id created_at }"`.
+    pub async fn insert_selecting<T: Serialize + DeviiTrait, R: DeserializeOwned + 'static>(&self, object: &T, selection: &str) -> Result<R, DeviiError> {
+        self.run(insert_selecting_operation(object, selection)?).await
+    }
+    pub fn insert_selecting_sync<T: Serialize + DeviiTrait, R: DeserializeOwned + 'static>(&self, object: &T, selection: &str) -> Result<R, DeviiError> {
+        self.run_sync(insert_selecting_operation(object, selection)?)
+    }
 
-        let id_from_insert = result.data.remove(&(format!("create_{}", snake_type))).unwrap();
-        
-        Ok(id_from_insert)
+    /// Inserts every object in one request and returns the outcome of each in the order of
+    /// `objects`: its id, or the errors Devii reported for it. Objects that were inserted stay
+    /// inserted when others fail.
+    pub async fn batch_insert<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + Debug>(&self, objects: Vec<&T>) -> Result<Vec<Result<T::Id, DeviiError>>, DeviiError> {
+        if objects.is_empty() {
+            return Ok(vec![]);
+        }
+        self.run(batch_insert_each_operation(&objects)?).await
+    }
+    pub fn batch_insert_sync<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + Debug>(&self, objects: Vec<&T>) -> Result<Vec<Result<T::Id, DeviiError>>, DeviiError> {
+        if objects.is_empty() {
+            return Ok(vec![]);
+        }
+        self.run_sync(batch_insert_each_operation(&objects)?)
     }
 
-    pub async fn batch_insert<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + Debug + ?Sized>(&self, objects: Vec<&T>) -> Result<String, Box<dyn std::error::Error>> {
-        // create query. 
-        // create Devii Trait
-            // Trait will include insert_query & input_type
+    pub async fn fetch<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, filter: impl Into<String>) -> Result<Vec<T>, DeviiError> {
+        self.run(fetch_operation::<T>(filter.into())?).await
+    }
+    pub fn fetch_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, filter: impl Into<String>) -> Result<Vec<T>, DeviiError> {
+        self.run_sync(fetch_operation::<T>(filter.into())?)
+    }
 
-        // build inputs object with HashMap u16 Value as below
-        // build query by using foreach:(1_input: input_type) foreach insert_query(1)
-        let query_string = get_query_string_from_vec(&objects);
+    /// Fetches the records matching `options`, letting Devii filter, sort and page them.
+    pub async fn fetch_with_options<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, options: FetchOptions) -> Result<Vec<T>, DeviiError> {
+        self.run(fetch_options_operation::<T>(options)?).await
+    }
+    pub fn fetch_with_options_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, options: FetchOptions) -> Result<Vec<T>, DeviiError> {
+        self.run_sync(fetch_options_operation::<T>(options)?)
+    }
 
-        let mut insert_objects: HashMap<String, Value> = HashMap::new(); 
-        let mut counter = 0;
-        let mut objects_iter = objects.iter();
+    /// Streams the records matching `options`, fetching `page_size` records per request so only
+    /// one page is held in memory. Pages are walked with `offset`/`limit`, starting at
+    /// `options.offset` and stopping after `options.limit` records or once a page comes back
+    /// short. Records are ordered by the key columns of `T` unless `options.ordering` says otherwise.
+    pub fn fetch_stream<T: DeserializeOwned + DeviiTrait + Send + 'static>(&self, options: FetchOptions, page_size: u64) -> impl Stream<Item = Result<T, DeviiError>> + Send + 'static {
+        let state = (self.clone(), Pager::new::<T>(options, page_size), VecDeque::new());
+
+        stream::unfold(state, |(client, mut pager, mut buffer)| async move {
+            loop {
+                if let Some(record) = buffer.pop_front() {
+                    return Some((Ok(record), (client, pager, buffer)));
+                }
+                let page = match pager.next_page()? {
+                    Ok(page) => page,
+                    Err(e) => return Some((Err(e), (client, pager, buffer)))
+                };
+                let requested = page.limit.unwrap_or_default();
 
-        // TODO: make more custom and part of the Devii Trait
-        while let Some(object) = objects_iter.next(){
-            insert_objects.insert(format!("input_{}", counter), object.graphql_inputs());
-            counter = counter + 1;
+                let result = match fetch_options_operation::<T>(page) {
+                    Ok(operation) => client.run(operation).await,
+                    Err(e) => Err(e)
+                };
+                match result {
+                    Ok(records) => {
+                        pager.advance(requested, records.len());
+                        buffer.extend(records);
+                    },
+                    Err(e) => {
+                        pager.done = true;
+                        return Some((Err(e), (client, pager, buffer)));
+                    }
+                }
+            }
+        })
+    }
+    /// Blocking version of `fetch_stream`.
+    pub fn fetch_stream_sync<T: DeserializeOwned + DeviiTrait>(&self, options: FetchOptions, page_size: u64) -> FetchIter<T> {
+        FetchIter {
+            client: self.clone(),
+            pager: Pager::new::<T>(options, page_size),
+            buffer: VecDeque::new()
         }
+    }
 
+    pub async fn delete<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, object: &T) -> Result<(), DeviiError> {
+        self.run(delete_operation(object)?).await
+    }
+    pub fn delete_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, object: &T) -> Result<(), DeviiError> {
+        self.run_sync(delete_operation(object)?)
+    }
 
-        let query = DeviiQueryBatchInsertOptions{ 
-            query: query_string,
-            variables: serde_json::to_string(&insert_objects)?
-        };
-
-        let query_result = self.query::<DeviiQueryResult<HashMap<String, String>>, DeviiQueryBatchInsertOptions>(&query).await;
+    /// Updates the record `id` to the values of `object` and returns the updated record.
+    pub async fn update<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait + 'static>(&self, object: T, id: T::Id) -> Result<T, DeviiError>{
+        self.run(update_operation(object, id, &T::fetch_fields())?).await
+    }
+    pub fn update_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait + 'static>(&self, object: T, id: T::Id) -> Result<T, DeviiError>{
+        self.run_sync(update_operation(object, id, &T::fetch_fields())?)
+    }
 
-        if let Err(e) = query_result {
-            bail!("Failed Query {:?} Error: {:?}", &query, e);
-        }
+    /// `update` decoding the `selection` of the updated record instead, e.g. `"{ id updated_at }"`.
+    pub async fn update_selecting<T: DeserializeOwned + Serialize + NamedType + DeviiTrait, R: DeserializeOwned + 'static>(&self, object: T, id: T::Id, selection: &str) -> Result<R, DeviiError>{
+        self.run(update_operation(object, id, selection)?).await
+    }
+    pub fn update_selecting_sync<T: DeserializeOwned + Serialize + NamedType + DeviiTrait, R: DeserializeOwned + 'static>(&self, object: T, id: T::Id, selection: &str) -> Result<R, DeviiError>{
+        self.run_sync(update_operation(object, id, selection)?)
+    }
 
-        // let id_from_insert = result.data.remove(&(format!("create_{}", snake_type))).unwrap();
-        Ok("success".to_string())
+    /// The record with primary key `id`, `DeviiError::NotFound` if there is none.
+    pub async fn fetch_by_id<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait + 'static>(&self, id: &T::Id) -> Result<T, DeviiError> {
+        self.run(fetch_by_id_operation::<T>(id)?).await
     }
-    pub fn batch_insert_sync<T: DeserializeOwned + Serialize + NamedType + DeviiTrait + Debug + ?Sized>(&self, objects: Vec<&T>) -> Result<String, Box<dyn std::error::Error>> {
-        // create query. 
-        // create Devii Trait
-            // Trait will include insert_query & input_type
+    pub fn fetch_by_id_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait + 'static>(&self, id: &T::Id) -> Result<T, DeviiError> {
+        self.run_sync(fetch_by_id_operation::<T>(id)?)
+    }
+}
 
-        // build inputs object with HashMap u16 Value as below
-        // build query by using foreach:(1_input: input_type) foreach insert_query(1)
-        let query_string = get_query_string_from_vec(&objects);
+// An operation whose mutations select the key columns of a record.
+pub(crate) type KeyOperation<R> = Operation<DeviiQueryResult<Map<String, Value>>, R>;
+
+// The `graphql_inputs` of a record sent on insert. Which fields are columns comes from the field
+// metadata, so JSON and array columns are sent as they are. Unset columns are left out so the
+// database defaults apply.
+pub(crate) fn insert_input(inputs: Value) -> Result<Map<String, Value>, DeviiError> {
+    match inputs {
+        Value::Object(mut map) => {
+            map.retain(|_, value| !value.is_null());
+            Ok(map)
+        },
+        _ => Err(DeviiError::InvalidInput("Struct not evaluated as an Object!".to_string()))
+    }
+}
 
-        let mut insert_objects: HashMap<String, Value> = HashMap::new(); 
-        let mut counter = 0;
-        let mut objects_iter = objects.iter();
+fn insert_operation<T: Serialize + DeviiTrait>(object: &T) -> Result<KeyOperation<T::Id>, DeviiError> {
+    let columns = T::id_columns();
+    let selection = format!("{{ {} }}", columns.join(" "));
+    Ok(insert_input_operation(T::table_name(), &selection, insert_input(object.graphql_inputs())?)?
+        .map(move |record: Map<String, Value>| read_key(&columns, &record)))
+}
 
-        // TODO: make more custom and part of the Devii Trait
-        while let Some(object) = objects_iter.next(){
-            insert_objects.insert(format!("input_{}", counter), object.graphql_inputs());
-            counter = counter + 1;
-        }
+fn insert_selecting_operation<T: Serialize + DeviiTrait, R: DeserializeOwned + 'static>(object: &T, selection: &str) -> Result<Operation<DeviiQueryResult<R>, R>, DeviiError> {
+    insert_input_operation(T::table_name(), selection, insert_input(object.graphql_inputs())?)
+}
 
-        let query = DeviiQueryBatchInsertOptions{ 
-            query: query_string,
-            variables: serde_json::to_string(&insert_objects)?
-        };
+// A `create_` mutation of `input` decoding `selection` of the new record.
+pub(crate) fn insert_input_operation<R: DeserializeOwned + 'static>(snake_type: String, selection: &str, input: Map<String, Value>) -> Result<Operation<DeviiQueryResult<R>, R>, DeviiError> {
+    let insert = Insert {
+        input
+    };
 
-        let query_result = self.query_sync::<DeviiQueryResult<HashMap<String, String>>, DeviiQueryBatchInsertOptions>(&query);
+    let query_string = format!("mutation insert ($input: {}Input){{
+        create_{} (input: $input)
+        {}
+      }}",
+      snake_type,
+      snake_type,
+      selection
+    );
 
-        if let Err(e) = query_result {
-            bail!("Failed Query {:?} Error: {:?}", &query, e);
-        }
+    let query = DeviiQueryInsertOptions{ 
+        query: query_string,
+        variables: insert
+    };
 
-        // let id_from_insert = result.data.remove(&(format!("create_{}", snake_type))).unwrap();
-        Ok("success".to_string())
-    }
+    Operation::new(&query, move |mut result: DeviiQueryResult<R>| {
+        result.take(&format!("create_{}", snake_type))
+    })
+}
 
+// The outcome of every object of a batched insert, in input order.
+pub(crate) type EachKeyOperation<I> = KeyOperation<Vec<Result<I, DeviiError>>>;
 
-    pub async fn fetch<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, filter: String) -> Result<Vec<T>, Box<dyn std::error::Error>> {
-        let snake_type = T::short_type_name().to_case(Case::Snake);
+// The aliases that failed are named by `GraphQLError::field`, the others were inserted.
+pub(crate) fn batch_insert_each_operation<T: DeviiTrait>(objects: &Vec<&T>) -> Result<EachKeyOperation<T::Id>, DeviiError> {
+    let query = batch_insert_query(objects)?;
 
-        let query_string = format!("query fetch($filter: String){{
-            {} (filter: $filter)
-              {}
-          }}",
-          snake_type,
-          T::fetch_fields() 
-        );
+    let count = objects.len();
+    Operation::new(&query, move |mut result: DeviiQueryResult<Map<String, Value>>| {
+        Ok((0..count).map(|i| take_inserted_id::<T>(&mut result, i)).collect())
+    })
+}
 
-        let fetch_variables = FetchOptionsBuilder::default().filter(filter).build().unwrap();
-        
-        let query = DeviiQueryOptions{ 
-            query: query_string,
-            variables: Some(fetch_variables)
-        };
+fn batch_insert_query<T: DeviiTrait>(objects: &Vec<&T>) -> Result<DeviiQueryBatchInsertOptions, DeviiError> {
+    // build inputs object with HashMap u16 Value as below
+    // build query by using foreach:(1_input: input_type) foreach insert_query(1)
+    let query_string = get_query_string_from_vec(objects);
 
-        let mut result = self.query::<DeviiQueryResult<Vec<T>>, DeviiQueryOptions>(&query).await?;
+    let mut insert_objects: HashMap<String, Value> = HashMap::new(); 
+    let mut counter = 0;
+    let mut objects_iter = objects.iter();
 
-        let data_result = result.data.remove(&(format!("{}", snake_type))).unwrap();
-        
-        Ok(data_result)
+    // TODO: make more custom and part of the Devii Trait
+    while let Some(object) = objects_iter.next(){
+        insert_objects.insert(format!("input_{}", counter), Value::Object(insert_input(object.graphql_inputs())?));
+        counter = counter + 1;
     }
-    pub fn fetch_sync<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, filter: String) -> Result<Vec<T>, Box<dyn std::error::Error>> {
-        let snake_type = T::short_type_name().to_case(Case::Snake);
 
-        let query_string = format!("query fetch($filter: String){{
-            {} (filter: $filter)
-              {}
-          }}",
-          snake_type,
-          T::fetch_fields() 
-        );
+    Ok(DeviiQueryBatchInsertOptions{ 
+        query: query_string,
+        variables: serde_json::to_string(&insert_objects)?
+    })
+}
 
-        let fetch_variables = FetchOptionsBuilder::default().filter(filter).build().unwrap();
-        
-        let query = DeviiQueryOptions{ 
-            query: query_string,
-            variables: Some(fetch_variables)
-        };
+// `insert_N` holds the key columns of the Nth object of a batch.
+fn take_inserted_id<T: DeviiTrait>(result: &mut DeviiQueryResult<Map<String, Value>>, index: usize) -> Result<T::Id, DeviiError> {
+    let record = result.take(&format!("insert_{}", index))?;
+    read_key(&T::id_columns(), &record)
+}
 
-        let mut result = self.query_sync::<DeviiQueryResult<Vec<T>>, DeviiQueryOptions>(&query)?;
+// Tracks the position of `fetch_stream` and `fetch_stream_sync` in the table.
+struct Pager {
+    options: FetchOptions,
+    page_size: u64,
+    offset: u64,
+    remaining: Option<u64>,
+    done: bool
+}
 
-        let data_result = result.data.remove(&(format!("{}", snake_type))).unwrap();
-        
-        Ok(data_result)
+impl Pager {
+    fn new<T: DeviiTrait>(mut options: FetchOptions, page_size: u64) -> Self {
+        // Offsets only page reliably over a stable order.
+        if options.ordering.is_none() {
+            options.ordering = Some(T::id_columns().into_iter().map(String::from).collect());
+        }
+        Pager {
+            offset: options.offset.unwrap_or(0),
+            remaining: options.limit,
+            options,
+            page_size,
+            done: false
+        }
     }
 
-    pub async fn delete<T: DeserializeOwned + Serialize + NamedType + Default + DeviiTrait>(&self, object: &T) -> Result<(), Box<dyn std::error::Error>> {
-        let snake_type = T::short_type_name().to_case(Case::Snake);
-
-        let query_string = format!("mutation delete{{
-            delete_{} ({}){{
-                __typename
-            }}
-          }}",
-          snake_type,
-          object.delete_input() 
-        );
+    // The options of the next page, `None` once every record was fetched.
+    fn next_page(&mut self) -> Option<Result<FetchOptions, DeviiError>> {
+        if self.done {
+            return None;
+        }
+        if self.page_size == 0 {
+            self.done = true;
+            return Some(Err(DeviiError::InvalidInput("page_size must be at least 1".to_string())));
+        }
 
-        let query = DeviiQueryOptions{ 
-            query: query_string,
-            variables: None
-        };
+        let limit = self.remaining.map(|r| r.min(self.page_size)).unwrap_or(self.page_size);
+        if limit == 0 {
+            return None;
+        }
+        Some(Ok(FetchOptions {
+            filter: self.options.filter.clone(),
+            ordering: self.options.ordering.clone(),
+            offset: Some(self.offset),
+            limit: Some(limit)
+        }))
+    }
 
-        let result = self.query::<DeviiQueryResult<HashMap<String, String>>, DeviiQueryOptions>(&query).await;
+    fn advance(&mut self, requested: u64, received: usize) {
+        let received = received as u64;
 
-        if let Err(e) = result{
-            bail!("Object not deleted: {:?}", e)
+        self.offset += received;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(received);
         }
-        
-        Ok(())
+        self.done = received < requested;
+    }
+}
+
+/// The records of `DeviiClient::fetch_stream_sync`, fetched one page at a time.
+pub struct FetchIter<T> {
+    client: DeviiClient,
+    pager: Pager,
+    buffer: VecDeque<T>
+}
+
+impl<T: DeserializeOwned + DeviiTrait> Iterator for FetchIter<T> {
+    type Item = Result<T, DeviiError>;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.buffer.pop_front() {
+                return Some(Ok(record));
+            }
+            let page = match self.pager.next_page()? {
+                Ok(page) => page,
+                Err(e) => return Some(Err(e))
+            };
+            let requested = page.limit.unwrap_or_default();
+
+            match fetch_options_operation::<T>(page).and_then(|operation| self.client.run_sync(operation)) {
+                Ok(records) => {
+                    self.pager.advance(requested, records.len());
+                    self.buffer.extend(records);
+                },
+                Err(e) => {
+                    self.pager.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
     }
+}
 
-    pub async fn update<T: DeserializeOwned + Serialize + NamedType+ Default>(&self, object: T, id: u64) -> Result<T, Box<dyn std::error::Error>>{
+fn fetch_operation<T: DeserializeOwned + DeviiTrait>(filter: String) -> Result<Operation<DeviiQueryResult<Vec<T>>, Vec<T>>, DeviiError> {
+    fetch_options_operation(FetchOptionsBuilder::default().filter(filter).build().unwrap())
+}
 
-        let update = Update {
-            input : object,
-            id: id
-        };
+fn fetch_options_operation<T: DeserializeOwned + DeviiTrait>(options: FetchOptions) -> Result<Operation<DeviiQueryResult<Vec<T>>, Vec<T>>, DeviiError> {
+    let snake_type = T::table_name();
+    let (definitions, arguments) = options.declarations();
 
-        let snake_type = T::short_type_name().to_case(Case::Snake);
+    let query_string = format!("query fetch{}{{
+        {} {}
+          {}
+      }}",
+      definitions,
+      snake_type,
+      arguments,
+      T::fetch_fields() 
+    );
 
-        let query_string = format!("mutation update ($input: {}Input, $id: ID!){{
-            update_{} (id: $id, input: $input)
-            {}
-         }}",
-          snake_type,
-          snake_type,
-          parse_value(&serde_json::to_value(T::default()).unwrap(), Some("id".to_string()))
-        );
+    let query = DeviiQueryOptions{ 
+        query: query_string,
+        variables: Some(options)
+    };
 
-        let query = DeviiQueryUpdateOptions{ 
-            query: query_string,
-            variables: update
-        };
+    Operation::new(&query, move |mut result: DeviiQueryResult<Vec<T>>| {
+        result.take(&snake_type)
+    })
+}
 
-        let mut result = self.query::<DeviiQueryResult<T>, DeviiQueryUpdateOptions<T>>(&query).await?;
+fn fetch_by_id_operation<T: DeserializeOwned + Serialize + Default + DeviiTrait + 'static>(id: &T::Id) -> Result<Operation<DeviiQueryResult<Vec<T>>, T>, DeviiError> {
+    let options = FetchOptions {
+        filter: Some(key_filter(&T::id_columns(), id)?.to_string()),
+        limit: Some(1),
+        ..Default::default()
+    };
+    Ok(fetch_options_operation::<T>(options)?
+        .map(|mut records: Vec<T>| records.pop().ok_or(DeviiError::NotFound)))
+}
 
-        let type_from_update = result.data.remove(&(format!("update_{}", snake_type))).unwrap();
-        
-        Ok(type_from_update)
-    }
-    pub fn update_sync<T: DeserializeOwned + Serialize + NamedType+ Default>(&self, object: T, id: u64) -> Result<T, Box<dyn std::error::Error>>{
+fn delete_operation<T: DeviiTrait>(object: &T) -> Result<Operation<DeviiQueryResult<HashMap<String, String>>, ()>, DeviiError> {
+    let snake_type = T::table_name();
+    let id = object.id().ok_or(DeviiError::InvalidInput(format!("delete on {} needs the key of the record", snake_type)))?;
 
-        let update = Update {
-            input : object,
-            id: id
-        };
+    let query_string = format!("mutation delete{{
+        delete_{} ({}){{
+            __typename
+        }}
+      }}",
+      snake_type,
+      key_arguments(&T::id_columns(), &id)
+    );
 
-        let snake_type = T::short_type_name().to_case(Case::Snake);
+    let query = DeviiQueryOptions{ 
+        query: query_string,
+        variables: None
+    };
 
-        let query_string = format!("mutation update ($input: {}Input, $id: ID!){{
-            update_{} (id: $id, input: $input)
-            {}
-         }}",
-          snake_type,
-          snake_type,
-          parse_value(&serde_json::to_value(T::default()).unwrap(), Some("id".to_string()))
-        );
+    Operation::new(&query, move |mut result: DeviiQueryResult<HashMap<String, String>>| {
+        result.take(&format!("delete_{}", snake_type))?;
+        Ok(())
+    })
+}
 
-        let query = DeviiQueryUpdateOptions{ 
-            query: query_string,
-            variables: update
-        };
+fn update_operation<T: DeserializeOwned + Serialize + DeviiTrait, R: DeserializeOwned + 'static>(object: T, id: T::Id, selection: &str) -> Result<Operation<DeviiQueryResult<R>, R>, DeviiError> {
+    let update = Update {
+        input : object.graphql_inputs()
+    };
 
-        let mut result = self.query_sync::<DeviiQueryResult<T>, DeviiQueryUpdateOptions<T>>(&query)?;
+    let snake_type = T::table_name();
 
-        let type_from_update = result.data.remove(&(format!("update_{}", snake_type))).unwrap();
-        
-        Ok(type_from_update)
-    }
+    let query_string = format!("mutation update ($input: {}Input){{
+        update_{} ({}, input: $input)
+        {}
+     }}",
+      snake_type,
+      snake_type,
+      key_arguments(&T::id_columns(), &id),
+      selection
+    );
+
+    let query = DeviiQueryUpdateOptions{ 
+        query: query_string,
+        variables: update
+    };
+
+    Operation::new(&query, move |mut result: DeviiQueryResult<R>| {
+        result.take(&format!("update_{}", snake_type))
+    })
 }
 
 pub trait DeviiQueryResultType{}
 
+/// The body of a GraphQL response. `data` holds one entry per root field (or alias), which is
+/// `None` when that field failed; the reasons are listed in `errors`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviiQueryResult<T> {
-    pub data: HashMap<String, T>
+    #[serde(default = "Option::default")]
+    pub data: Option<HashMap<String, Option<T>>>,
+    #[serde(default)]
+    pub errors: Vec<GraphQLError>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct InsertIdResult {
-    // Result comes back from devii as a string but should be a unique identifier
-    id: String
+impl<T> DeviiQueryResult<T> {
+    /// Removes the data of `field`, or returns the errors that explain why it is missing.
+    pub fn take(&mut self, field: &str) -> Result<T, DeviiError> {
+        let value = self.data.as_mut().and_then(|data| data.remove(field));
+
+        if let Some(Some(value)) = value {
+            return Ok(value);
+        }
+
+        let field_errors: Vec<GraphQLError> = self.errors_for(field).into_iter().cloned().collect();
+        if !field_errors.is_empty() {
+            return Err(DeviiError::GraphQL(field_errors));
+        }
+        if !self.errors.is_empty() {
+            return Err(DeviiError::GraphQL(self.errors.clone()));
+        }
+        match value {
+            // The field resolved to null without an error, i.e. there was no such record.
+            Some(None) => Err(DeviiError::NotFound),
+            _ => Err(DeviiError::MissingData { field: field.to_string() })
+        }
+    }
+
+    /// The errors reported for `field`, e.g. one alias of a batched mutation.
+    pub fn errors_for(&self, field: &str) -> Vec<&GraphQLError> {
+        self.errors.iter().filter(|e| e.field() == Some(field)).collect()
+    }
+
+    /// Whether some root fields resolved while others failed.
+    pub fn is_partial(&self) -> bool {
+        let has_data = match &self.data {
+            Some(data) => data.values().any(|v| v.is_some()),
+            None => false
+        };
+        has_data && !self.errors.is_empty()
+    }
+}
+
+// Body Devii answers with when the request is rejected before reaching GraphQL.
+#[derive(Deserialize, Debug)]
+struct DeviiErrorBody {
+    error: String
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphQLErrorBody {
+    errors: Vec<GraphQLError>
+}
+
+// Parses a Devii response body, turning error bodies into the matching `DeviiError`. Only 401 and
+// 403 are authentication failures, any other error status is `Http`.
+fn decode_response<T: DeserializeOwned>(status: u16, body: String) -> Result<T, DeviiError> {
+    // Checked first because every field of `DeviiQueryResult` is optional.
+    if let Ok(e) = serde_json::from_str::<DeviiErrorBody>(&body) {
+        return Err(match status {
+            401 if e.error == "Token expired." => DeviiError::TokenExpired,
+            401 | 403 => DeviiError::Auth(e.error),
+            _ => DeviiError::Http { status, message: e.error }
+        });
+    }
+
+    match serde_json::from_str(&body) {
+        Ok(r) => Ok(r),
+        Err(source) => {
+            if let Ok(e) = serde_json::from_str::<GraphQLErrorBody>(&body) {
+                if !e.errors.is_empty() {
+                    return Err(DeviiError::GraphQL(e.errors));
+                }
+            }
+            if !(200..300).contains(&status) {
+                return Err(DeviiError::Http { status, message: body });
+            }
+            Err(DeviiError::Decode { body, source })
+        }
+    }
 }
 
 
 // May be usuable in the future -> For automatic FetchFields trait
+#[allow(dead_code)]
 fn parse_value(value: &Value, additional_fields: Option<String>) -> String {
     let mut additional_field_string = "".to_string(); 
 
@@ -563,10 +1019,19 @@ fn get_query_string_from_vec<T: DeviiTrait>(objects: &Vec<&T>) -> String {
 // cargo test foo -- --test-threads 3
 #[cfg(test)]
 mod tests {
-    use dotenv;
+    use crate::testing::MockDevii;
+    use crate::filter::col;
+    use futures::StreamExt;
+    use std::collections::HashMap;
     use crate::devii::DeviiClient;
     use crate::devii::DeviiClientOptions;
-    use crate::test_struct::{TestStruct, TestOneToMany};
+    use crate::devii::{FetchOptions, FetchOptionsBuilder, HttpOptionsBuilder};
+    use crate::transport::{BlockingTransport, BoxFuture, HttpRequest, HttpResponse, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::test_struct::{connect, TestCategory, TestStruct, TestComposite, TestJsonColumns, TestOneToMany, TestManyToOne, TestRenamed, TestCamelCase};
+    use crate::devii::{FieldInfo, FieldKind, decode_response, DeviiQueryResult};
+    use crate::error::DeviiError;
     use crate::devii::parse_value;
     use crate::devii::DeviiTrait;
 
@@ -581,18 +1046,173 @@ mod tests {
     fn fetch_fields_test() {
         let value = TestOneToMany::fetch_fields();
 
-        assert_eq!("{ id, value, test_many_to_one_collection { id, value, test_one_to_many_id, test_one_to_many { id, value } } }".to_string()
+        assert_eq!("{ id, value, test_many_to_one_collection { id, value, test_one_to_many_id } }".to_string()
         , value)
     }
+    #[test]
+    fn self_referential_fetch_fields_test() {
+        assert_eq!(TestCategory::fetch_fields(), "{ id, name, parent_id, children { id, name, parent_id }, parent { id, name, parent_id } }");
+        assert_eq!(TestCategory::scalar_fields(), "{ id, name, parent_id }");
+    }
+    #[test]
+    fn derive_insert_query_test() {
+        let value = TestManyToOne::default();
+
+        assert_eq!("create_test_many_to_one (input: $input_0 ){ id }".to_string(), value.insert_query("input_0".to_string()));
+        assert_eq!("test_many_to_oneInput".to_string(), value.input_type());
+    }
+    #[test]
+    fn derive_graphql_inputs_test() {
+        let mut value = TestOneToMany::new();
+        value.id = Some(7);
+
+        let inputs = value.graphql_inputs();
+
+        assert_eq!(inputs, serde_json::json!({ "id": 7, "value": "OneToMany" }));
+        assert_eq!("id: 7".to_string(), value.delete_input());
+    }
+    #[test]
+    fn derive_attributes_test() {
+        let value = TestRenamed { id: Some(3), label: "renamed".to_string(), cached: Some("cached".to_string()) };
+
+        assert_eq!("test_struct".to_string(), TestRenamed::table_name());
+        assert_eq!("{ id, label: string }".to_string(), TestRenamed::fetch_fields());
+        assert_eq!(value.graphql_inputs(), serde_json::json!({ "string": "renamed" }));
+        assert_eq!("test_structInput".to_string(), value.input_type());
+
+        let camel = TestCamelCase { id: Some(1), display_name: "camel".to_string(), sort_order: 2 };
+        assert_eq!("{ id, displayName, sortOrder: sort_key }".to_string(), TestCamelCase::fetch_fields());
+        assert_eq!(camel.graphql_inputs(), serde_json::json!({ "id": 1, "displayName": "camel", "sort_key": 2 }));
+
+        assert_eq!(TestManyToOne::fields()[3], FieldInfo {
+            name: "test_one_to_many",
+            graphql_name: "test_one_to_many",
+            kind: FieldKind::BelongsTo { fk: "test_one_to_many_id" },
+            rust_type: "TestOneToMany",
+            optional: true
+        });
+    }
+
+    #[test]
+    fn decode_response_errors_test() {
+        let expired = decode_response::<DeviiQueryResult<String>>(401, "{\"error\":\"Token expired.\",\"status\":401}".to_string());
+        assert!(matches!(expired, Err(DeviiError::TokenExpired)));
+
+        let unauthorized = decode_response::<DeviiQueryResult<String>>(401, "{\"error\":\"Invalid credentials\",\"status\":401}".to_string());
+        assert!(matches!(unauthorized, Err(DeviiError::Auth(message)) if message == "Invalid credentials"));
+
+        let graphql = decode_response::<DeviiQueryResult<String>>(400, "{\"errors\":[{\"message\":\"Unknown field\"}]}".to_string());
+        assert!(matches!(graphql.unwrap().take("test_struct"), Err(DeviiError::GraphQL(errors)) if errors[0].message == "Unknown field"));
+
+        let garbage = decode_response::<DeviiQueryResult<String>>(200, "not json".to_string());
+        assert!(matches!(garbage, Err(DeviiError::Decode { body, .. }) if body == "not json"));
+
+        let not_found = decode_response::<DeviiQueryResult<String>>(404, "{\"error\":\"Not found.\",\"status\":404}".to_string());
+        assert!(matches!(not_found, Err(DeviiError::Http { status: 404, message }) if message == "Not found."));
+
+        let unavailable = decode_response::<DeviiQueryResult<String>>(503, "Service Unavailable".to_string());
+        assert!(matches!(unavailable, Err(DeviiError::Http { status: 503, .. })));
+    }
+
+    #[test]
+    fn query_result_partial_errors_test() {
+        let body = r#"{
+            "data": { "insert_0": { "id": "1" }, "insert_1": null },
+            "errors": [{
+                "message": "duplicate key value",
+                "locations": [{ "line": 2, "column": 9 }],
+                "path": ["insert_1"],
+                "extensions": { "code": "23505" }
+            }]
+        }"#;
+        let mut result = decode_response::<DeviiQueryResult<HashMap<String, String>>>(200, body.to_string()).unwrap();
+
+        assert!(result.is_partial());
+        assert_eq!(result.errors[0].locations[0].line, 2);
+        assert_eq!(result.errors[0].extensions.as_ref().unwrap()["code"], "23505");
+        assert_eq!(result.take("insert_0").unwrap().get("id").unwrap(), "1");
+
+        assert!(matches!(result.take("insert_1"), Err(DeviiError::GraphQL(e)) if e[0].field() == Some("insert_1")));
+    }
+
+    #[test]
+    fn query_result_null_data_test() {
+        let mut result = decode_response::<DeviiQueryResult<HashMap<String, String>>>(200, "{\"data\": { \"update_test_struct\": null }}".to_string()).unwrap();
+        assert!(matches!(result.take("update_test_struct"), Err(DeviiError::NotFound)));
+
+        let mut result = decode_response::<DeviiQueryResult<HashMap<String, String>>>(200, "{\"data\": {}}".to_string()).unwrap();
+        assert!(matches!(result.take("update_test_struct"), Err(DeviiError::MissingData { .. })));
+    }
+
+    #[test]
+    fn http_options_build_client_test() {
+        let options = HttpOptionsBuilder::default()
+            .pool_max_idle_per_host(4)
+            .pool_idle_timeout(Duration::from_secs(30))
+            .build()
+            .unwrap();
+
+        assert_eq!(options.pool_max_idle_per_host, Some(4));
+        assert!(options.client().is_ok());
+        assert!(options.blocking_client().is_ok());
+    }
+
+    // Answers every request from memory and remembers what was sent.
+    #[derive(Debug, Default)]
+    struct CannedTransport {
+        requests: Mutex<Vec<HttpRequest>>
+    }
+
+    impl CannedTransport {
+        fn respond(&self, request: HttpRequest) -> HttpResponse {
+            let body = if request.url.ends_with("/auth") {
+                r#"{"access_token":"access","refresh_token":"refresh","message":"ok","routes":{"base":"http://devii","query":"http://devii/query","roles_pbac":"http://devii/roles_pbac"}}"#
+            } else {
+                r#"{"data":{"create_test_struct":{"id":"5"}}}"#
+            };
+            self.requests.lock().unwrap().push(request);
+            HttpResponse { status: 200, body: body.to_string() }
+        }
+    }
+
+    impl Transport for CannedTransport {
+        fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, DeviiError>> {
+            Box::pin(async move { Ok(self.respond(request)) })
+        }
+    }
+
+    impl BlockingTransport for CannedTransport {
+        fn send(&self, request: HttpRequest) -> Result<HttpResponse, DeviiError> {
+            Ok(self.respond(request))
+        }
+    }
+
+    #[test]
+    fn custom_transport_test() {
+        let transport = Arc::new(CannedTransport::default());
+        let options = DeviiClientOptions::new("login".to_string(), "password".to_string(), "http://devii".to_string(), 1)
+            .transport(transport.clone())
+            .blocking_transport(transport.clone());
+
+        let client = DeviiClient::connect_sync(options).unwrap();
+
+        let async_result = tokio_test::block_on(client.insert(&TestStruct::new())).unwrap();
+        let sync_result = client.insert_sync(&TestStruct::new()).unwrap();
+
+        assert_eq!(async_result, sync_result);
+        assert_eq!(async_result, 5);
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].body.as_ref().unwrap()["login"], "login");
+        assert_eq!(requests[1].bearer, Some("access".to_string()));
+        assert_eq!(requests[1].body, requests[2].body);
+    }
 
     #[test]
     fn client_connect() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
 
         let client = tokio_test::block_on(DeviiClient::connect(options));
 
@@ -607,36 +1227,28 @@ mod tests {
     
     #[test]
     fn client_connect_returns_query_url() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
 
         let client_result = tokio_test::block_on(DeviiClient::connect(options));
 
         if let Ok(res) = client_result {
-            assert_eq!(res.routes.query, format!("{}/tenant13/query",dotenv::var("DEVII_BASE_URL").unwrap()));
+            assert_eq!(res.routes.query, format!("{}/tenant13/query", server.base_url()));
         } else {
             assert!(false);
         }
     }
     #[test]
     fn insert_struct_test_one_to_many_struct() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
 
         let one_to_many_struct = TestOneToMany::new();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         
         let result = tokio_test::block_on(client.insert(&one_to_many_struct));
-        let layer1_id = result.unwrap().remove("id").unwrap().parse::<u64>().unwrap();
+        let layer1_id = result.unwrap();
         
         let mut test_many_to_one_collection = one_to_many_struct.test_many_to_one_collection.unwrap();
         let mut iter = test_many_to_one_collection.iter_mut();
@@ -658,12 +1270,8 @@ mod tests {
 
     #[test]
     fn insert_delete_struct_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         
@@ -672,7 +1280,7 @@ mod tests {
         if let Ok(r) = result {
             println!("successfully inserted: {:?}", r);
             let mut t_struct = TestStruct::new();
-            t_struct.id = Some(r.get("id").unwrap().parse::<u64>().unwrap());
+            t_struct.id = Some(r);
             let delete_result = tokio_test::block_on(client.delete(&t_struct));
             
             if let Ok(_) = delete_result {
@@ -690,12 +1298,8 @@ mod tests {
     }
     #[test]
     fn insert_batch_struct_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         let test_struct1 = TestStruct::new();
@@ -712,12 +1316,8 @@ mod tests {
     }
     #[test]
     fn insert_batch_sync_struct_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = DeviiClient::connect_sync(options).unwrap();
         let test_struct1 = TestStruct::new();
@@ -734,13 +1334,104 @@ mod tests {
     }
 
     #[test]
-    fn insert_struct_min_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
+    fn insert_update_selection_test() {
+        let (server, client) = connect();
+        let parent = client.insert_sync(&TestOneToMany { value: "parent".to_string(), ..Default::default() }).unwrap();
+
+        let child = TestManyToOne { value: "child".to_string(), test_one_to_many_id: Some(parent), ..Default::default() };
+        let inserted = tokio_test::block_on(client.insert_returning(&child)).unwrap();
+        assert_eq!(inserted.id, Some(1));
+        assert_eq!(inserted.test_one_to_many.map(|p| p.value), Some("parent".to_string()));
+
+        let selected: HashMap<String, String> = client.insert_selecting_sync(&child, "{ value }").unwrap();
+        assert_eq!(selected["value"], "child");
+
+        let renamed = TestManyToOne { value: "renamed".to_string(), ..child };
+        let updated = client.update_sync(renamed, 1).unwrap();
+        assert_eq!((updated.value.as_str(), updated.test_one_to_many_id), ("renamed", Some(parent)));
+        assert!(server.queries().last().unwrap()["query"].as_str().unwrap().contains(&TestManyToOne::fetch_fields()));
+
+        let value: HashMap<String, String> = tokio_test::block_on(client.update_selecting(TestManyToOne::default(), 2, "{ value }")).unwrap();
+        assert_eq!(value["value"], "");
+    }
+
+    #[test]
+    fn insert_json_and_array_columns_test() {
+        let (server, client) = connect();
+
+        let record = TestJsonColumns {
+            id: None,
+            tags: vec!["a".to_string(), "b".to_string()],
+            scores: Some(vec![1, 2]),
+            metadata: serde_json::json!({ "source": "import", "retries": [1, 2] })
         };
+        let id = client.insert_sync(&record).unwrap();
+        assert_eq!(server.queries()[0]["variables"]["input"], serde_json::json!({
+            "tags": ["a", "b"],
+            "scores": [1, 2],
+            "metadata": { "source": "import", "retries": [1, 2] }
+        }));
+
+        let updated = client.update_sync(TestJsonColumns { scores: None, ..record }, id).unwrap();
+        assert_eq!((updated.tags, updated.scores), (vec!["a".to_string(), "b".to_string()], None));
+        assert_eq!(updated.metadata["retries"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn composite_key_test() {
+        let (server, client) = connect();
+        assert_eq!(TestComposite::id_columns(), vec!["hash", "index"]);
+
+        let record = TestComposite { hash: "hashy".to_string(), index: 8, value: "first".to_string() };
+        let id = client.insert_sync(&record).unwrap();
+        assert_eq!(id, ("hashy".to_string(), 8));
+        assert_eq!(record.id(), Some(id.clone()));
+
+        let updated = client.update_sync(TestComposite { value: "second".to_string(), ..record.clone() }, id.clone()).unwrap();
+        assert_eq!(updated.value, "second");
+        let query = server.queries().last().unwrap()["query"].as_str().unwrap().to_string();
+        assert!(query.contains("update_test_composite (hash: \"hashy\", index: 8, input: $input)"));
+
+        let fetched: TestComposite = tokio_test::block_on(client.fetch_by_id(&id)).unwrap();
+        assert_eq!(fetched.value, "second");
+
+        client.delete_sync(&record).unwrap();
+        assert!(matches!(client.fetch_by_id_sync::<TestComposite>(&id), Err(DeviiError::NotFound)));
+        let query = server.queries().last().unwrap()["query"].as_str().unwrap().to_string();
+        assert!(!query.contains("null"));
+
+        assert!(matches!(client.delete_sync(&TestStruct::new()), Err(DeviiError::InvalidInput(_))));
+
+        server.insert_row("test_struct", serde_json::to_value(TestStruct::new()).unwrap());
+        let fetched: TestStruct = client.fetch_by_id_sync(&1).unwrap();
+        assert_eq!(fetched.id, Some(1));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_key_test() {
+        use crate::id::key_arguments;
+        use crate::test_struct::TestUuid;
+
+        let (server, client) = connect();
+        let id: uuid::Uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8".parse().unwrap();
+        assert_eq!(key_arguments(&["id"], &id), "id: \"67e55044-10b1-426f-9247-bb680e5fe0c8\"");
+
+        let record = TestUuid { id: Some(id), value: "first".to_string() };
+        assert_eq!(client.insert_sync(&record).unwrap(), id);
+        let fetched: TestUuid = tokio_test::block_on(client.fetch_by_id(&id)).unwrap();
+        assert_eq!((fetched.id, fetched.value.as_str()), (Some(id), "first"));
+
+        client.delete_sync(&record).unwrap();
+        let query = server.queries().last().unwrap()["query"].as_str().unwrap().to_string();
+        assert!(query.contains("delete_test_uuid (id: \"67e55044-10b1-426f-9247-bb680e5fe0c8\")"));
+        assert!(matches!(client.fetch_by_id_sync::<TestUuid>(&id), Err(DeviiError::NotFound)));
+    }
+
+    #[test]
+    fn insert_struct_min_test() {
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         
@@ -757,19 +1448,15 @@ mod tests {
 
     #[test]
     fn fetch_struct_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         
         let insert_result = tokio_test::block_on(client.insert(&TestStruct::new()));
 
-        let fetch_result: Result<Vec<TestStruct>, Box<dyn std::error::Error>> = tokio_test::block_on(client.fetch(
-            format!("id = {}", insert_result.unwrap().remove("id").unwrap().parse::<u64>().unwrap())));
+        let fetch_result: Result<Vec<TestStruct>, DeviiError> = tokio_test::block_on(client.fetch(
+            format!("id = {}", insert_result.unwrap())));
         
         if let Ok(mut record) = fetch_result {
             assert_eq!(record.pop().unwrap()._char, 'c')
@@ -781,12 +1468,8 @@ mod tests {
 
     #[test]
     fn fetch_struct_parent_child_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
 
@@ -794,7 +1477,7 @@ mod tests {
         
         let insert_result = tokio_test::block_on(client.insert(&parent_struct));
         
-        let new_parent_id = insert_result.unwrap().remove("id").unwrap().parse::<u64>().unwrap();
+        let new_parent_id = insert_result.unwrap();
 
         let mut test_many_to_one_collection = parent_struct.test_many_to_one_collection.unwrap();
         let mut child_iter = test_many_to_one_collection.iter_mut();
@@ -804,7 +1487,7 @@ mod tests {
             let _child_id = tokio_test::block_on(client.insert(child));
         }
 
-        let fetch_result: Result<Vec<TestOneToMany>, Box<dyn std::error::Error>> = tokio_test::block_on(client.fetch(format!("id = {}",new_parent_id)));
+        let fetch_result: Result<Vec<TestOneToMany>, DeviiError> = tokio_test::block_on(client.fetch(format!("id = {}",new_parent_id)));
         
         if let Ok(mut record) = fetch_result {
             println!("{:?}", record);
@@ -820,14 +1503,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fetch_with_options_test() {
+        let (server, client) = connect();
+
+        for _ in 0..5 {
+            client.insert_sync(&TestStruct::new()).unwrap();
+        }
+
+        let options = FetchOptionsBuilder::default()
+            .ordering(vec!["id desc".to_string()])
+            .offset(1)
+            .limit(2)
+            .build()
+            .unwrap();
+        let records: Vec<TestStruct> = tokio_test::block_on(client.fetch_with_options(options)).unwrap();
+        let ids: Vec<u64> = records.iter().map(|r| r.id.unwrap()).collect();
+        assert_eq!(ids, vec![4, 3]);
+
+        // Only the options that were set are declared and sent.
+        let queries = server.queries();
+        let variables = &queries.last().unwrap()["variables"];
+        assert_eq!(variables.as_object().unwrap().len(), 3);
+        assert!(variables.get("filter").is_none());
+
+        let records: Vec<TestStruct> = client.fetch_with_options_sync(FetchOptions::default()).unwrap();
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn fetch_with_filter_test() {
+        let (_server, client) = connect();
+
+        let mut quoted = TestStruct::new();
+        quoted.string = "it's".to_string();
+        client.insert_sync(&quoted).unwrap();
+        client.insert_sync(&TestStruct::new_min()).unwrap();
+
+        let records: Vec<TestStruct> = client.fetch_sync(col("string").eq("it's").and(col("_i8").gt(0))).unwrap();
+        assert_eq!(records.len(), 1);
+
+        // Quotes in values can't escape the literal.
+        let records: Vec<TestStruct> = client.fetch_sync(col("string").eq("x' or string != 'x")).unwrap();
+        assert_eq!(records.len(), 0);
+
+        let options = FetchOptionsBuilder::default().filter(col("id").in_(vec![1, 2])).build().unwrap();
+        let records: Vec<TestStruct> = client.fetch_with_options_sync(options).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn fetch_stream_test() {
+        let (server, client) = connect();
+
+        for _ in 0..7 {
+            client.insert_sync(&TestStruct::new()).unwrap();
+        }
+        let inserts = server.queries().len();
+
+        let records: Vec<Result<TestStruct, DeviiError>> = tokio_test::block_on(
+            client.fetch_stream(FetchOptions::default(), 3).collect()
+        );
+        let ids: Vec<u64> = records.into_iter().map(|r| r.unwrap().id.unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(server.queries().len() - inserts, 3);
+
+        let options = FetchOptionsBuilder::default().offset(1).limit(5).build().unwrap();
+        let ids: Vec<u64> = client.fetch_stream_sync::<TestStruct>(options, 3)
+            .map(|r| r.unwrap().id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![2, 3, 4, 5, 6]);
+
+        let composite: Vec<TestComposite> = client.fetch_stream_sync(FetchOptions::default(), 3).map(|r| r.unwrap()).collect();
+        assert!(composite.is_empty());
+        assert_eq!(server.queries().last().unwrap()["variables"]["ordering"], serde_json::json!(["hash", "index"]));
+
+        let mut empty = client.fetch_stream_sync::<TestStruct>(FetchOptions::default(), 0);
+        assert!(matches!(empty.next(), Some(Err(DeviiError::InvalidInput(_)))));
+        assert!(empty.next().is_none());
+    }
+
     #[test]
     fn update_basic_struct_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
+        let server = MockDevii::start();
+        let options = server.options();
         
         let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
         
@@ -837,13 +1596,13 @@ mod tests {
 
         let insert_result = tokio_test::block_on(client.insert(&testing_struct));
 
-        testing_struct_dup.id = Some(insert_result.unwrap().remove("id").unwrap().parse::<u64>().unwrap());
+        testing_struct_dup.id = Some(insert_result.unwrap());
 
         let id_to_update = testing_struct_dup.id.clone().unwrap();
 
         testing_struct_dup.string = "I changed this".to_string();
 
-        let update_result: Result<TestStruct, Box<dyn std::error::Error>> = tokio_test::block_on(client.update(testing_struct_dup, id_to_update));
+        let update_result: Result<TestStruct, DeviiError> = tokio_test::block_on(client.update(testing_struct_dup, id_to_update));
         
         if let Ok(record) = update_result {
             assert_eq!(record.string, "I changed this".to_string());
@@ -854,34 +1613,38 @@ mod tests {
     }
     #[test]
     fn query_expired_token_handle_test() {
-        let options = DeviiClientOptions {
-            login:  dotenv::var("DEVII_USERNAME").unwrap(),
-            password: dotenv::var("DEVII_PASSWORD").unwrap(),
-            tenantid:  dotenv::var("DEVII_TENANT_ID").unwrap().parse::<u32>().unwrap(),
-            base:  dotenv::var("DEVII_BASE_URL").unwrap()
-        };
-            
-        let expired_token =  dotenv::var("DEVII_EXPIRED_TOKEN").unwrap();
+        let server = MockDevii::start();
+        let options = server.options();
 
-        let mut client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
+        let client = tokio_test::block_on(DeviiClient::connect(options)).unwrap();
+        let client_clone = client.clone();
+        let expired_token = client.access_token();
 
-        client.set_access_token(expired_token);
+        server.expire_tokens();
         
         let testing_struct = TestStruct::new();
-        let testing_struct_dup = TestStruct::new();
-
 
+        // The expired token is refreshed and the insert retried.
         let insert_result = tokio_test::block_on(client.insert(&testing_struct));
         
-        if let Err(e) = insert_result {
-            if e.to_string().contains("Token expired.") {
-                assert!(true)
-            } else {
-                assert!(false);
-            }
+        if let Ok(_) = insert_result {
+            assert_ne!(client.access_token(), expired_token);
+            assert_eq!(client_clone.access_token(), client.access_token());
         } else {
             println!("{:?}", insert_result);
             assert!(false);
         }
+
+        // Clones hitting the expiry together refresh it once.
+        server.expire_tokens();
+        let refreshes = server.refreshes();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let client = client.clone();
+            std::thread::spawn(move || client.insert_sync(&TestStruct::new()))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(server.refreshes() - refreshes, 1);
     }
 }
@@ -0,0 +1,106 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A single entry of the `errors` array of a GraphQL response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GraphQLError {
+    pub message: String,
+    #[serde(default)]
+    pub locations: Vec<GraphQLErrorLocation>,
+    /// Path to the field that failed, starting with the (aliased) root field, e.g. `["insert_1"]`.
+    #[serde(default)]
+    pub path: Vec<GraphQLPathSegment>,
+    #[serde(default)]
+    pub extensions: Option<Map<String, Value>>
+}
+
+impl GraphQLError {
+    /// The root field (or alias) the error belongs to, if the server reported a path.
+    pub fn field(&self) -> Option<&str> {
+        match self.path.first() {
+            Some(GraphQLPathSegment::Field(field)) => Some(field.as_str()),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GraphQLErrorLocation {
+    pub line: u64,
+    pub column: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum GraphQLPathSegment {
+    Field(String),
+    Index(u64)
+}
+
+#[derive(Debug)]
+pub enum DeviiError {
+    /// Devii rejected the credentials or the access token.
+    Auth(String),
+    /// The access token has expired, the body was `{"error":"Token expired.","status":401}`.
+    TokenExpired,
+    /// Devii answered with an error status other than 401 or 403, e.g. 404 for an unknown route.
+    Http { status: u16, message: String },
+    /// The query reached Devii but returned GraphQL errors.
+    GraphQL(Vec<GraphQLError>),
+    /// The request couldn't be sent or the response couldn't be read.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    /// The arguments couldn't be serialized into GraphQL variables.
+    Encode(serde_json::Error),
+    /// The response body couldn't be parsed into the requested type.
+    Decode { body: String, source: serde_json::Error },
+    /// The response had no data for `field`.
+    MissingData { field: String },
+    /// The record the operation targets doesn't exist.
+    NotFound,
+    /// The arguments can't be turned into a valid query.
+    InvalidInput(String),
+}
+
+impl fmt::Display for DeviiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviiError::Auth(message) => write!(f, "Devii authentication failed: {}", message),
+            DeviiError::TokenExpired => write!(f, "Token expired."),
+            DeviiError::Http { status, message } => write!(f, "Devii answered {}: {}", status, message),
+            DeviiError::GraphQL(errors) => {
+                let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+                write!(f, "GraphQL errors: {}", messages.join("; "))
+            },
+            DeviiError::Transport(e) => write!(f, "Request to Devii failed: {}", e),
+            DeviiError::Encode(e) => write!(f, "Failed to serialize variables: {}", e),
+            DeviiError::Decode { body, source } => write!(f, "Failed to Parse struct from Result: {:?}, Error: {}", body, source),
+            DeviiError::MissingData { field } => write!(f, "No data returned for `{}`", field),
+            DeviiError::NotFound => write!(f, "Record not found"),
+            DeviiError::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DeviiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviiError::Transport(e) => Some(e.as_ref()),
+            DeviiError::Encode(e) => Some(e),
+            DeviiError::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for DeviiError {
+    fn from(e: reqwest::Error) -> Self {
+        DeviiError::Transport(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for DeviiError {
+    fn from(e: serde_json::Error) -> Self {
+        DeviiError::Encode(e)
+    }
+}
@@ -0,0 +1,325 @@
+//! Builds the SQL-like `filter` argument of Devii queries. Values are always rendered as quoted
+//! literals and unusual column names as quoted identifiers, so nothing passed in can change the
+//! shape of the filter.
+//!
+//! ```text
+//! col("id").eq(5).and(col("value").like("foo%"))  =>  id = 5 and value like 'foo%'
+//! ```
+
+use std::fmt;
+
+/// A column to compare, see `col`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    name: String
+}
+
+/// Starts a condition on the column `name`.
+pub fn col(name: impl Into<String>) -> Column {
+    Column { name: name.into() }
+}
+
+/// Negates `filter`, same as `!filter`.
+pub fn not(filter: Filter) -> Filter {
+    !filter
+}
+
+/// A literal on the right hand side of a condition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Text(String)
+}
+
+macro_rules! filter_value_from {
+    ($variant:ident($target:ty): $($source:ty),*) => {
+        $(
+            impl From<$source> for FilterValue {
+                fn from(value: $source) -> Self {
+                    FilterValue::$variant(value as $target)
+                }
+            }
+        )*
+    };
+}
+
+filter_value_from!(Int(i64): i8, i16, i32, i64, isize);
+filter_value_from!(UInt(u64): u8, u16, u32, u64, usize);
+filter_value_from!(Float(f64): f32, f64);
+
+impl From<bool> for FilterValue {
+    fn from(value: bool) -> Self {
+        FilterValue::Bool(value)
+    }
+}
+
+impl From<char> for FilterValue {
+    fn from(value: char) -> Self {
+        FilterValue::Text(value.to_string())
+    }
+}
+
+impl From<&str> for FilterValue {
+    fn from(value: &str) -> Self {
+        FilterValue::Text(value.to_string())
+    }
+}
+
+impl From<String> for FilterValue {
+    fn from(value: String) -> Self {
+        FilterValue::Text(value)
+    }
+}
+
+impl From<&String> for FilterValue {
+    fn from(value: &String) -> Self {
+        FilterValue::Text(value.clone())
+    }
+}
+
+impl<T: Into<FilterValue>> From<Option<T>> for FilterValue {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(FilterValue::Null)
+    }
+}
+
+impl FilterValue {
+    /// The literal for a JSON scalar, `None` for arrays and objects.
+    pub(crate) fn from_json(value: &serde_json::Value) -> Option<FilterValue> {
+        use serde_json::Value;
+        Some(match value {
+            Value::Null => FilterValue::Null,
+            Value::Bool(b) => FilterValue::Bool(*b),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(u), _) => FilterValue::UInt(u),
+                (None, Some(i)) => FilterValue::Int(i),
+                _ => FilterValue::Float(n.as_f64()?)
+            },
+            Value::String(s) => FilterValue::Text(s.clone()),
+            Value::Array(_) | Value::Object(_) => return None
+        })
+    }
+}
+
+impl fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterValue::Null => write!(f, "null"),
+            FilterValue::Bool(b) => write!(f, "{}", b),
+            FilterValue::Int(i) => write!(f, "{}", i),
+            FilterValue::UInt(u) => write!(f, "{}", u),
+            // Postgres spells the non finite floats as strings.
+            FilterValue::Float(x) if x.is_nan() => write!(f, "'NaN'"),
+            FilterValue::Float(x) if x.is_infinite() => write!(f, "'{}Infinity'", if *x < 0.0 { "-" } else { "" }),
+            FilterValue::Float(x) => write!(f, "{:?}", x),
+            FilterValue::Text(s) => write!(f, "'{}'", s.replace('\'', "''"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Compare(&'static str, FilterValue),
+    In(Vec<FilterValue>),
+    IsNull,
+    IsNotNull,
+    Between(FilterValue, FilterValue),
+    Like(String),
+    ILike(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Condition(Column, Condition),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>)
+}
+
+/// A Devii filter. Combine filters with `and`, `or` and `not`, and pass them anywhere a filter
+/// string is expected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter(Expr);
+
+impl Column {
+    fn condition(self, condition: Condition) -> Filter {
+        Filter(Expr::Condition(self, condition))
+    }
+
+    pub fn eq(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare("=", value.into()))
+    }
+
+    pub fn ne(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare("!=", value.into()))
+    }
+
+    pub fn lt(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare("<", value.into()))
+    }
+
+    pub fn le(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare("<=", value.into()))
+    }
+
+    pub fn gt(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare(">", value.into()))
+    }
+
+    pub fn ge(self, value: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Compare(">=", value.into()))
+    }
+
+    /// Matches any of `values`. An empty list matches nothing.
+    pub fn in_<V: Into<FilterValue>>(self, values: impl IntoIterator<Item = V>) -> Filter {
+        self.condition(Condition::In(values.into_iter().map(Into::into).collect()))
+    }
+
+    pub fn is_null(self) -> Filter {
+        self.condition(Condition::IsNull)
+    }
+
+    pub fn is_not_null(self) -> Filter {
+        self.condition(Condition::IsNotNull)
+    }
+
+    /// Matches values from `low` to `high`, both included.
+    pub fn between(self, low: impl Into<FilterValue>, high: impl Into<FilterValue>) -> Filter {
+        self.condition(Condition::Between(low.into(), high.into()))
+    }
+
+    /// Case sensitive pattern match, `%` matches any run of characters and `_` exactly one.
+    pub fn like(self, pattern: impl Into<String>) -> Filter {
+        self.condition(Condition::Like(pattern.into()))
+    }
+
+    /// Case insensitive `like`.
+    pub fn ilike(self, pattern: impl Into<String>) -> Filter {
+        self.condition(Condition::ILike(pattern.into()))
+    }
+}
+
+impl Filter {
+    pub fn and(self, other: Filter) -> Filter {
+        Filter(match (self.0, other.0) {
+            (Expr::And(mut a), Expr::And(b)) => { a.extend(b); Expr::And(a) },
+            (Expr::And(mut a), b) => { a.push(b); Expr::And(a) },
+            (a, b) => Expr::And(vec![a, b])
+        })
+    }
+
+    pub fn or(self, other: Filter) -> Filter {
+        Filter(match (self.0, other.0) {
+            (Expr::Or(mut a), Expr::Or(b)) => { a.extend(b); Expr::Or(a) },
+            (Expr::Or(mut a), b) => { a.push(b); Expr::Or(a) },
+            (a, b) => Expr::Or(vec![a, b])
+        })
+    }
+}
+
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter(Expr::Not(Box::new(self.0)))
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plain = self.name.chars().next().map(|c| c == '_' || c.is_ascii_alphabetic()).unwrap_or(false)
+            && self.name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+            && !is_keyword(&self.name);
+
+        if plain {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "\"{}\"", self.name.replace('"', "\"\""))
+        }
+    }
+}
+
+fn is_keyword(name: &str) -> bool {
+    ["and", "or", "not", "in", "is", "null", "true", "false", "like", "ilike", "between"]
+        .iter()
+        .any(|k| k.eq_ignore_ascii_case(name))
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // `= null` never matches in SQL.
+            Condition::Compare("=", FilterValue::Null) => write!(f, "is null"),
+            Condition::Compare("!=", FilterValue::Null) => write!(f, "is not null"),
+            Condition::Compare(op, value) => write!(f, "{} {}", op, value),
+            Condition::In(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "in ({})", values.join(", "))
+            },
+            Condition::IsNull => write!(f, "is null"),
+            Condition::IsNotNull => write!(f, "is not null"),
+            Condition::Between(low, high) => write!(f, "between {} and {}", low, high),
+            Condition::Like(pattern) => write!(f, "like {}", FilterValue::from(pattern)),
+            Condition::ILike(pattern) => write!(f, "ilike {}", FilterValue::from(pattern))
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Nested groups are always parenthesized so precedence never depends on the server.
+        let group = |expr: &Expr| match expr {
+            Expr::And(_) | Expr::Or(_) => format!("({})", expr),
+            _ => expr.to_string()
+        };
+        match self {
+            // `x in ()` isn't valid SQL, an empty list matches nothing.
+            Expr::Condition(_, Condition::In(values)) if values.is_empty() => write!(f, "false"),
+            Expr::Condition(column, condition) => write!(f, "{} {}", column, condition),
+            Expr::And(exprs) => write!(f, "{}", exprs.iter().map(group).collect::<Vec<_>>().join(" and ")),
+            Expr::Or(exprs) => write!(f, "{}", exprs.iter().map(group).collect::<Vec<_>>().join(" or ")),
+            Expr::Not(expr) => write!(f, "not ({})", expr)
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<Filter> for String {
+    fn from(filter: Filter) -> Self {
+        filter.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{col, not};
+
+    #[test]
+    fn render_filter_test() {
+        let filter = col("id").eq(5).and(col("value").like("foo%"));
+        assert_eq!(filter.to_string(), "id = 5 and value like 'foo%'");
+
+        let filter = col("id").in_(vec![1, 2]).or(col("parent_id").is_null()).and(not(col("score").between(1.5, 2)));
+        assert_eq!(filter.to_string(), "(id in (1, 2) or parent_id is null) and not (score between 1.5 and 2)");
+
+        assert_eq!(col("id").in_(Vec::<u64>::new()).to_string(), "false");
+        assert_eq!(col("deleted_at").eq(None::<String>).to_string(), "deleted_at is null");
+        assert_eq!((!col("flag").eq(true)).to_string(), "not (flag = true)");
+    }
+
+    #[test]
+    fn render_filter_escapes_test() {
+        assert_eq!(col("value").eq("x' or 1 = 1 --").to_string(), "value = 'x'' or 1 = 1 --'");
+        assert_eq!(col("my col\" or 1 = 1").eq(1).to_string(), "\"my col\"\" or 1 = 1\" = 1");
+        assert_eq!(col("and").eq(f64::NEG_INFINITY).to_string(), "\"and\" = '-Infinity'");
+    }
+}
@@ -0,0 +1,306 @@
+//! Inserting a record together with its related records. `#[derive(Devii)]` implements
+//! `GraphNode` from the `has_many` and `belongs_to` fields, and `insert_graph` walks them:
+//!
+//! - parents (`belongs_to`) that weren't inserted yet are inserted first and their id is copied
+//!   into the node's foreign key column,
+//! - the node itself is inserted unless it already was,
+//! - children (`has_many`) get the node's id in their foreign key column and are inserted the
+//!   same way.
+//!
+//! A record was inserted when every key column is an `Option` holding a value. Such records are
+//! only linked, never inserted again: a foreign key that changed is sent as an `update_` of that
+//! column alone. Records with non-`Option` keys get their key before insert, so `insert_graph`
+//! can't tell whether they exist and always inserts them.
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::devii::{insert_input, insert_input_operation, DeviiClient, DeviiQueryResult, DeviiTrait, KeyOperation, Operation};
+use crate::error::DeviiError;
+use crate::id::key_arguments;
+
+/// A record `insert_graph` can insert along with its relations. Implemented by `#[derive(Devii)]`.
+pub trait GraphNode: Send {
+    fn graph_table(&self) -> String;
+    fn graph_inputs(&self) -> Value;
+    /// The value of the first key column, which children store in their foreign key.
+    fn graph_id(&self) -> Option<String>;
+    /// Whether the record was inserted already, i.e. every key column is `Some`.
+    fn graph_inserted(&self) -> bool;
+    /// The arguments selecting the record in a mutation, `None` while a key column is `None`.
+    fn graph_key_arguments(&self) -> Option<String>;
+    /// The primary key columns, see `DeviiTrait::id_columns`.
+    fn graph_id_columns(&self) -> Vec<&'static str>;
+    /// Writes `id` into the id or foreign key column `column`.
+    fn set_graph_column(&mut self, column: &str, id: &str) -> Result<(), DeviiError>;
+    fn graph_parents(&mut self) -> Vec<GraphParent<'_>>;
+    fn graph_children(&mut self) -> Vec<GraphChildren<'_>>;
+}
+
+/// A `belongs_to` relation, whose id goes into the `fk` column of the node.
+pub struct GraphParent<'a> {
+    pub fk: &'static str,
+    pub node: &'a mut dyn GraphNode
+}
+
+/// A `has_many` relation, whose records get the node's id in their `fk` column.
+pub struct GraphChildren<'a> {
+    pub fk: &'static str,
+    pub nodes: Vec<&'a mut dyn GraphNode>
+}
+
+// Devii hands out ids as strings, id columns are usually numbers.
+#[doc(hidden)]
+pub fn parse_id<V: DeserializeOwned>(id: &str) -> Result<V, DeviiError> {
+    if let Ok(n) = id.parse::<u64>() {
+        if let Ok(value) = serde_json::from_value(Value::from(n)) {
+            return Ok(value);
+        }
+    }
+    serde_json::from_value(Value::from(id))
+        .map_err(|source| DeviiError::Decode { body: id.to_string(), source })
+}
+
+#[doc(hidden)]
+pub fn key_arguments_of<T: DeviiTrait>(record: &T) -> Option<String> {
+    Some(key_arguments(&T::id_columns(), &record.id()?))
+}
+
+#[doc(hidden)]
+pub fn id_to_string<V: Serialize>(id: &V) -> Option<String> {
+    match serde_json::to_value(id).ok()? {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        other => Some(other.to_string())
+    }
+}
+
+impl DeviiClient {
+    /// Inserts `graph` and every related record reachable through its `belongs_to` and `has_many`
+    /// fields, and returns it with every id and foreign key filled in.
+    ///
+    /// Each record is its own request, so a failure leaves the records inserted before it in place.
+    pub async fn insert_graph<T: GraphNode>(&self, mut graph: T) -> Result<T, DeviiError> {
+        self.insert_node(&mut graph).await?;
+        Ok(graph)
+    }
+
+    pub fn insert_graph_sync<T: GraphNode>(&self, mut graph: T) -> Result<T, DeviiError> {
+        self.insert_node_sync(&mut graph)?;
+        Ok(graph)
+    }
+
+    fn insert_node<'a>(&'a self, node: &'a mut dyn GraphNode) -> BoxFuture<'a, Result<(), DeviiError>> {
+        Box::pin(async move {
+            for parent in node.graph_parents() {
+                if !parent.node.graph_inserted() {
+                    self.insert_node(&mut *parent.node).await?;
+                }
+            }
+            let changed = link_parents(node)?;
+
+            if !node.graph_inserted() {
+                let mut inserted = self.run(node_insert_operation(node)?).await?;
+                set_inserted_id(node, &mut inserted)?;
+            } else if !changed.is_empty() {
+                self.run(link_operation(node, &changed)?).await?;
+            }
+
+            let id = node_id(node)?;
+            for children in node.graph_children() {
+                for child in children.nodes {
+                    if link(child, children.fk, &id)? && child.graph_inserted() {
+                        self.run(link_operation(child, &[children.fk])?).await?;
+                    }
+                    self.insert_node(child).await?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn insert_node_sync(&self, node: &mut dyn GraphNode) -> Result<(), DeviiError> {
+        for parent in node.graph_parents() {
+            if !parent.node.graph_inserted() {
+                self.insert_node_sync(parent.node)?;
+            }
+        }
+        let changed = link_parents(node)?;
+
+        if !node.graph_inserted() {
+            let mut inserted = self.run_sync(node_insert_operation(node)?)?;
+            set_inserted_id(node, &mut inserted)?;
+        } else if !changed.is_empty() {
+            self.run_sync(link_operation(node, &changed)?)?;
+        }
+
+        let id = node_id(node)?;
+        for children in node.graph_children() {
+            for child in children.nodes {
+                if link(child, children.fk, &id)? && child.graph_inserted() {
+                    self.run_sync(link_operation(child, &[children.fk])?)?;
+                }
+                self.insert_node_sync(child)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Copies the id of every parent into its foreign key column and returns the columns that changed.
+fn link_parents(node: &mut dyn GraphNode) -> Result<Vec<&'static str>, DeviiError> {
+    let mut links = vec![];
+    for parent in node.graph_parents() {
+        links.push((parent.fk, node_id(parent.node)?));
+    }
+    let mut changed = vec![];
+    for (fk, id) in links {
+        if link(node, fk, &id)? {
+            changed.push(fk);
+        }
+    }
+    Ok(changed)
+}
+
+// Writes `id` into the foreign key `column` and reports whether it changed.
+fn link(node: &mut dyn GraphNode, column: &str, id: &str) -> Result<bool, DeviiError> {
+    let before = node.graph_inputs().get(column).and_then(id_to_string);
+    node.set_graph_column(column, id)?;
+    Ok(before.as_deref() != Some(id))
+}
+
+fn node_id(node: &dyn GraphNode) -> Result<String, DeviiError> {
+    node.graph_id().ok_or(DeviiError::MissingData { field: "id".to_string() })
+}
+
+fn node_insert_operation(node: &dyn GraphNode) -> Result<KeyOperation<Map<String, Value>>, DeviiError> {
+    let selection = format!("{{ {} }}", node.graph_id_columns().join(" "));
+    insert_input_operation(node.graph_table(), &selection, insert_input(node.graph_inputs())?)
+}
+
+#[derive(Serialize, Debug)]
+struct LinkQuery {
+    query: String,
+    variables: LinkVariables
+}
+
+#[derive(Serialize, Debug)]
+struct LinkVariables {
+    input: Map<String, Value>
+}
+
+// Sends the foreign key `columns` of an inserted record, leaving its other columns alone.
+fn link_operation(node: &dyn GraphNode, columns: &[&str]) -> Result<Operation<DeviiQueryResult<Value>, ()>, DeviiError> {
+    let table = node.graph_table();
+    let key = node.graph_key_arguments().ok_or(DeviiError::MissingData { field: "id".to_string() })?;
+    let inputs = node.graph_inputs();
+    let input = columns.iter().map(|c| (c.to_string(), inputs.get(c).cloned().unwrap_or(Value::Null))).collect();
+
+    let query = LinkQuery {
+        query: format!("mutation link ($input: {}Input){{
+        update_{} ({}, input: $input){{ __typename }}
+     }}", table, table, key),
+        variables: LinkVariables { input }
+    };
+
+    Operation::new(&query, move |mut result: DeviiQueryResult<Value>| {
+        result.take(&format!("update_{}", table))?;
+        Ok(())
+    })
+}
+
+fn set_inserted_id(node: &mut dyn GraphNode, inserted: &mut Map<String, Value>) -> Result<(), DeviiError> {
+    for column in node.graph_id_columns() {
+        let id = inserted.remove(column).and_then(|id| id_to_string(&id)).ok_or(DeviiError::MissingData { field: column.to_string() })?;
+        node.set_graph_column(column, &id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviiError;
+    use crate::graph::GraphNode;
+    use crate::test_struct::{connect, insert_rows, TestComposite, TestManyToOne, TestOneToMany, TestOwned, TestSlug};
+
+    #[test]
+    fn insert_graph_test() {
+        let (server, client) = connect();
+
+        let parent = tokio_test::block_on(client.insert_graph(TestOneToMany::new())).unwrap();
+        assert_eq!(parent.id, Some(1));
+        let children = parent.test_many_to_one_collection.unwrap();
+        assert_eq!(children.iter().map(|c| c.id).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+        assert!(children.iter().all(|c| c.test_one_to_many_id == Some(1)));
+        assert!(server.rows("test_many_to_one").iter().all(|r| r["test_one_to_many_id"] == 1));
+
+        // The parent is inserted first, an already inserted one is only linked.
+        let child = TestManyToOne {
+            value: "orphan".to_string(),
+            test_one_to_many: Some(TestOneToMany { value: "new parent".to_string(), ..Default::default() }),
+            ..Default::default()
+        };
+        let child = client.insert_graph_sync(child).unwrap();
+        assert_eq!(child.test_one_to_many_id, Some(2));
+        assert_eq!(child.test_one_to_many.unwrap().id, Some(2));
+
+        let linked = TestManyToOne {
+            value: "linked".to_string(),
+            test_one_to_many: Some(TestOneToMany { id: Some(1), ..Default::default() }),
+            ..Default::default()
+        };
+        assert_eq!(client.insert_graph_sync(linked).unwrap().test_one_to_many_id, Some(1));
+        assert_eq!(server.rows("test_one_to_many").len(), 2);
+        assert_eq!(server.rows("test_many_to_one")[3]["test_one_to_many_id"], 1);
+
+        // Foreign keys and key columns are written back whatever they are named.
+        let owned = TestOwned { value: "owned".to_string(), parent: Some(TestOneToMany::default()), ..Default::default() };
+        let owned = client.insert_graph_sync(owned).unwrap();
+        assert_eq!((owned.id, owned.owner), (Some(1), Some(3)));
+        assert_eq!(server.rows("test_owned")[0]["owner"], 3);
+
+        let mut composite = TestComposite::default();
+        assert_eq!(composite.graph_id_columns(), vec!["hash", "index"]);
+        composite.set_graph_column("index", "9").unwrap();
+        assert_eq!(composite.index, 9);
+        assert!(matches!(composite.set_graph_column("value", "9"), Err(DeviiError::InvalidInput(_))));
+
+        // Keys that aren't `Option`s are set before insert, so those records are always inserted.
+        let slug = client.insert_graph_sync(TestSlug { slug: "".to_string(), value: "blank".to_string() }).unwrap();
+        assert_eq!(slug.slug, "");
+        assert_eq!(server.rows("test_slug")[0]["value"], "blank");
+        let hashed = TestComposite { hash: "hashy".to_string(), index: 1, value: "new".to_string() };
+        tokio_test::block_on(client.insert_graph(hashed)).unwrap();
+        assert_eq!(server.rows("test_composite")[0]["hash"], "hashy");
+    }
+
+    #[test]
+    fn insert_graph_links_existing_records_test() {
+        let (server, client) = connect();
+        let existing = insert_rows(&server, "test_many_to_one", ["first", "second"].map(|value| serde_json::json!({ "value": value })));
+
+        // Inserted children get only their foreign key updated, in the database too.
+        let children = existing.iter().map(|id| TestManyToOne { id: Some(*id), ..Default::default() }).collect();
+        let parent = TestOneToMany { value: "parent".to_string(), test_many_to_one_collection: Some(children), ..Default::default() };
+        let parent = tokio_test::block_on(client.insert_graph(parent)).unwrap();
+        let rows = server.rows("test_many_to_one");
+        assert!(rows.iter().all(|r| r["test_one_to_many_id"] == 1));
+        assert_eq!((&rows[0]["value"], &rows[1]["value"]), (&serde_json::json!("first"), &serde_json::json!("second")));
+        assert_eq!(server.queries().len(), 3);
+
+        // An unchanged foreign key isn't sent again.
+        client.insert_graph_sync(parent).unwrap();
+        assert_eq!(server.queries().len(), 3);
+
+        let moved = TestManyToOne {
+            id: Some(existing[0]),
+            test_one_to_many: Some(TestOneToMany { value: "new parent".to_string(), ..Default::default() }),
+            ..Default::default()
+        };
+        assert_eq!(client.insert_graph_sync(moved).unwrap().test_one_to_many_id, Some(2));
+        assert_eq!(server.rows("test_many_to_one")[0]["test_one_to_many_id"], 2);
+        assert_eq!(server.rows("test_many_to_one")[0]["value"], "first");
+    }
+}
@@ -0,0 +1,172 @@
+//! Primary keys. Every `DeviiTrait` type names its key type as `DeviiTrait::Id` and its key
+//! columns with `DeviiTrait::id_columns`; the key's values map onto those columns in order:
+//!
+//! ```text
+//! u64, String, Uuid       id: 7
+//! (String, u32)           hash: "hashy", index: 8
+//! ```
+//!
+//! Devii returns `ID` columns as strings, so every key type also reads its value from a string.
+
+use std::fmt::Debug;
+use serde_json::Value;
+
+use crate::error::DeviiError;
+use crate::filter::{col, Filter, FilterValue};
+
+/// A primary key: a single column like `u64`, `String` or `Uuid` (with the `uuid` feature), or
+/// a tuple of those for composite keys.
+pub trait DeviiId: Sized + Clone + Debug + Send + Sync + 'static {
+    /// How many columns the key spans.
+    const COLUMNS: usize = 1;
+
+    /// The value of each key column.
+    fn to_values(&self) -> Vec<Value>;
+
+    /// Reads the key back from the value of each key column.
+    fn from_values(values: &[Value]) -> Result<Self, DeviiError>;
+}
+
+fn single(values: &[Value]) -> Result<&Value, DeviiError> {
+    match values {
+        [value] => Ok(value),
+        _ => Err(DeviiError::InvalidInput(format!("expected 1 key column, found {}", values.len())))
+    }
+}
+
+fn invalid_key(value: &Value) -> DeviiError {
+    DeviiError::InvalidInput(format!("{} is not a valid key", value))
+}
+
+macro_rules! integer_id {
+    ($($ty:ty),*) => {
+        $(
+            impl DeviiId for $ty {
+                fn to_values(&self) -> Vec<Value> {
+                    vec![Value::from(*self)]
+                }
+
+                fn from_values(values: &[Value]) -> Result<Self, DeviiError> {
+                    let value = single(values)?;
+                    let parsed = match value {
+                        Value::Number(n) => n.to_string().parse().ok(),
+                        Value::String(s) => s.parse().ok(),
+                        _ => None
+                    };
+                    parsed.ok_or_else(|| invalid_key(value))
+                }
+            }
+        )*
+    };
+}
+
+integer_id!(u32, u64, i32, i64);
+
+impl DeviiId for String {
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::from(self.as_str())]
+    }
+
+    fn from_values(values: &[Value]) -> Result<Self, DeviiError> {
+        match single(values)? {
+            Value::String(s) => Ok(s.clone()),
+            Value::Number(n) => Ok(n.to_string()),
+            value => Err(invalid_key(value))
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl DeviiId for uuid::Uuid {
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::from(self.to_string())]
+    }
+
+    fn from_values(values: &[Value]) -> Result<Self, DeviiError> {
+        let value = single(values)?;
+        value.as_str().and_then(|s| s.parse().ok()).ok_or_else(|| invalid_key(value))
+    }
+}
+
+macro_rules! tuple_id {
+    ($($name:ident),*) => {
+        impl<$($name: DeviiId),*> DeviiId for ($($name,)*) {
+            const COLUMNS: usize = 0 $(+ $name::COLUMNS)*;
+
+            #[allow(non_snake_case)]
+            fn to_values(&self) -> Vec<Value> {
+                let ($($name,)*) = self;
+                let mut values = vec![];
+                $(values.extend($name.to_values());)*
+                values
+            }
+
+            #[allow(unused_assignments)]
+            fn from_values(values: &[Value]) -> Result<Self, DeviiError> {
+                if values.len() != Self::COLUMNS {
+                    return Err(DeviiError::InvalidInput(format!("expected {} key columns, found {}", Self::COLUMNS, values.len())));
+                }
+                let mut rest = values;
+                Ok(($({
+                    let (head, tail) = rest.split_at($name::COLUMNS);
+                    rest = tail;
+                    $name::from_values(head)?
+                },)*))
+            }
+        }
+    };
+}
+
+tuple_id!(A, B);
+tuple_id!(A, B, C);
+tuple_id!(A, B, C, D);
+
+/// The arguments selecting the record `id` in a mutation, e.g. `hash: "hashy", index: 8`.
+pub(crate) fn key_arguments<I: DeviiId>(columns: &[&str], id: &I) -> String {
+    // JSON scalars are valid GraphQL literals.
+    columns.iter().zip(id.to_values())
+        .map(|(column, value)| format!("{}: {}", column, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A filter matching the record `id`.
+pub(crate) fn key_filter<I: DeviiId>(columns: &[&str], id: &I) -> Result<Filter, DeviiError> {
+    let mut filter: Option<Filter> = None;
+    for (column, value) in columns.iter().zip(id.to_values()) {
+        let value = FilterValue::from_json(&value).ok_or_else(|| invalid_key(&value))?;
+        let condition = col(*column).eq(value);
+        filter = Some(match filter {
+            Some(filter) => filter.and(condition),
+            None => condition
+        });
+    }
+    filter.ok_or_else(|| DeviiError::InvalidInput("the key has no columns".to_string()))
+}
+
+/// Reads the key out of a record selected with its key columns.
+pub(crate) fn read_key<I: DeviiId>(columns: &[&str], record: &serde_json::Map<String, Value>) -> Result<I, DeviiError> {
+    let values: Vec<Value> = columns.iter()
+        .map(|column| record.get(*column).cloned().ok_or(DeviiError::MissingData { field: column.to_string() }))
+        .collect::<Result<_, _>>()?;
+    I::from_values(&values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::id::{key_arguments, key_filter, DeviiId};
+
+    #[test]
+    fn composite_key_test() {
+        let id = ("hashy".to_string(), 8u32);
+        assert_eq!(key_arguments(&["hash", "index"], &id), "hash: \"hashy\", index: 8");
+        assert_eq!(key_filter(&["hash", "index"], &id).unwrap().to_string(), "hash = 'hashy' and index = 8");
+        assert_eq!(<(String, u32)>::from_values(&[json!("hashy"), json!("8")]).unwrap(), id);
+        assert!(<(String, u32)>::from_values(&[json!("hashy")]).is_err());
+
+        assert_eq!(u64::from_values(&[json!("7")]).unwrap(), 7);
+        assert!(u64::from_values(&[json!("seven")]).is_err());
+        assert_eq!(String::from_values(&[json!(7)]).unwrap(), "7");
+    }
+}
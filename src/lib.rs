@@ -1,6 +1,27 @@
+pub mod aggregate;
+pub mod batch;
+pub mod delete;
 pub mod devii;
+pub mod error;
+pub mod filter;
+pub mod graph;
+pub mod id;
+pub mod patch;
+pub mod raw;
+pub mod schema;
+pub mod transport;
+pub mod upsert;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 mod test_struct;
 
+pub use devii_derive::Devii;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
+
 
 #[macro_use]
 extern crate derive_builder;
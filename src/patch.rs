@@ -0,0 +1,156 @@
+//! Partial updates. `update` sends every column of the struct, so it overwrites whatever changed
+//! since the struct was read. A `Patch` sends only the columns it names:
+//!
+//! ```text
+//! Patch::<TestManyToOne>::new().set("value", "renamed").set_null("test_one_to_many_id")
+//! ```
+//!
+//! Columns left out of the patch are left unchanged; `set_null` clears a column.
+
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::devii::{DeviiClient, DeviiQueryResult, DeviiTrait, FieldKind, Operation};
+use crate::error::DeviiError;
+use crate::id::key_arguments;
+
+/// The columns an `update_fields` changes, by their Devii names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch<T> {
+    values: Map<String, Value>,
+    table: PhantomData<T>
+}
+
+impl<T> Default for Patch<T> {
+    fn default() -> Self {
+        Patch { values: Map::new(), table: PhantomData }
+    }
+}
+
+impl<T: DeviiTrait> Patch<T> {
+    pub fn new() -> Self {
+        Patch::default()
+    }
+
+    /// Sets `column` to `value`.
+    pub fn set(mut self, column: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.values.insert(column.into(), value.into());
+        self
+    }
+
+    /// Sets `column` to null.
+    pub fn set_null(mut self, column: impl Into<String>) -> Self {
+        self.values.insert(column.into(), Value::Null);
+        self
+    }
+
+    /// The columns whose input differs between `before` and `after`, set to their value in `after`.
+    pub fn changes(before: &T, after: &T) -> Self {
+        let before = before.graphql_inputs();
+        let mut patch = Patch::new();
+        if let Value::Object(after) = after.graphql_inputs() {
+            for (column, value) in after {
+                if before.get(&column) != Some(&value) {
+                    patch.values.insert(column, value);
+                }
+            }
+        }
+        patch
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The columns set so far and their values.
+    pub fn values(&self) -> &Map<String, Value> {
+        &self.values
+    }
+
+    // Only input columns can be patched. Types without field metadata aren't checked.
+    fn validate(&self) -> Result<(), DeviiError> {
+        if self.values.is_empty() {
+            return Err(DeviiError::InvalidInput("the patch sets no columns".to_string()));
+        }
+        let fields = T::fields();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        for column in self.values.keys() {
+            let writable = fields.iter().any(|f| f.graphql_name == column && f.kind == FieldKind::Column);
+            if !writable {
+                return Err(DeviiError::InvalidInput(format!("{} has no writable column `{}`", T::table_name(), column)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PatchQuery<'a> {
+    query: String,
+    variables: PatchVariables<'a>
+}
+
+#[derive(Serialize, Debug)]
+struct PatchVariables<'a> {
+    input: &'a Map<String, Value>
+}
+
+impl DeviiClient {
+    /// Changes only the columns of `patch` on the record `id` and returns the updated record.
+    pub async fn update_fields<T: DeviiTrait>(&self, id: T::Id, patch: &Patch<T>) -> Result<T, DeviiError> {
+        self.run(patch_operation(id, patch)?).await
+    }
+
+    pub fn update_fields_sync<T: DeviiTrait>(&self, id: T::Id, patch: &Patch<T>) -> Result<T, DeviiError> {
+        self.run_sync(patch_operation(id, patch)?)
+    }
+}
+
+fn patch_operation<T: DeviiTrait>(id: T::Id, patch: &Patch<T>) -> Result<Operation<DeviiQueryResult<T>, T>, DeviiError> {
+    patch.validate()?;
+    let table = T::table_name();
+
+    let query = PatchQuery {
+        query: format!("mutation update ($input: {}Input){{
+        update_{} ({}, input: $input)
+        {}
+     }}", table, table, key_arguments(&T::id_columns(), &id), T::fetch_fields()),
+        variables: PatchVariables { input: &patch.values }
+    };
+
+    Operation::new(&query, move |mut result: DeviiQueryResult<T>| {
+        result.take(&format!("update_{}", table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviiError;
+    use crate::patch::Patch;
+    use crate::test_struct::{connect, TestManyToOne, TestStruct};
+
+    #[test]
+    fn update_fields_test() {
+        let (server, client) = connect();
+        server.insert_row("test_many_to_one", serde_json::json!({ "value": "original", "test_one_to_many_id": 3, "note": "kept" }));
+
+        let patch = Patch::<TestManyToOne>::new().set("value", "patched").set_null("test_one_to_many_id");
+        let updated = client.update_fields_sync(1, &patch).unwrap();
+        assert_eq!((updated.value.as_str(), updated.test_one_to_many_id), ("patched", None));
+        assert_eq!(server.queries()[0]["variables"]["input"], serde_json::json!({ "value": "patched", "test_one_to_many_id": null }));
+        assert_eq!(server.rows("test_many_to_one")[0]["note"], "kept");
+
+        let before = TestManyToOne { value: "patched".to_string(), ..Default::default() };
+        let after = TestManyToOne { value: "changed".to_string(), ..Default::default() };
+        let changes = Patch::changes(&before, &after);
+        assert_eq!(changes.values().keys().collect::<Vec<_>>(), vec!["value"]);
+        assert_eq!(tokio_test::block_on(client.update_fields(1, &changes)).unwrap().value, "changed");
+
+        let read_only = Patch::<TestStruct>::new().set("id", 2);
+        assert!(matches!(client.update_fields_sync(1, &read_only), Err(DeviiError::InvalidInput(_))));
+        assert!(matches!(client.update_fields_sync(404, &changes), Err(DeviiError::NotFound)));
+    }
+}
@@ -0,0 +1,107 @@
+//! Hand written GraphQL. `execute` sends a `RawQuery` as it is, with the same authentication,
+//! token refresh and error decoding as the typed methods, and decodes the `data` of the response.
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::devii::{DeviiClient, GraphQLQuery, Operation};
+use crate::error::{DeviiError, GraphQLError};
+
+/// A GraphQL document with its variables, e.g.
+/// `RawQuery::new("query recent ($limit: Int){ test_struct (limit: $limit){ id } }").variables(&vars)?`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct RawQuery {
+    pub query: String,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub variables: Value,
+    /// Which operation of the document to run, required when it has several.
+    #[serde(rename = "operationName", skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>
+}
+
+impl GraphQLQuery for RawQuery {}
+
+impl RawQuery {
+    pub fn new(query: impl Into<String>) -> Self {
+        RawQuery { query: query.into(), ..Default::default() }
+    }
+
+    /// Sets the variables to `variables` serialized, usually a map or a struct.
+    pub fn variables(mut self, variables: impl Serialize) -> Result<Self, DeviiError> {
+        self.variables = serde_json::to_value(variables)?;
+        Ok(self)
+    }
+
+    pub fn operation_name(mut self, operation_name: impl Into<String>) -> Self {
+        self.operation_name = Some(operation_name.into());
+        self
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RawResult {
+    #[serde(default)]
+    data: Option<Value>,
+    #[serde(default)]
+    errors: Vec<GraphQLError>
+}
+
+impl DeviiClient {
+    /// Runs `raw` and decodes the `data` of the response into `R`. Fails with every error of the
+    /// response if any field failed.
+    pub async fn execute<R: DeserializeOwned + 'static>(&self, raw: &RawQuery) -> Result<R, DeviiError> {
+        self.run(raw_operation(raw)?).await
+    }
+
+    pub fn execute_sync<R: DeserializeOwned + 'static>(&self, raw: &RawQuery) -> Result<R, DeviiError> {
+        self.run_sync(raw_operation(raw)?)
+    }
+}
+
+fn raw_operation<R: DeserializeOwned + 'static>(raw: &RawQuery) -> Result<Operation<RawResult, R>, DeviiError> {
+    if raw.query.trim().is_empty() {
+        return Err(DeviiError::InvalidInput("the query is empty".to_string()));
+    }
+    Operation::new(raw, |result: RawResult| {
+        if !result.errors.is_empty() {
+            return Err(DeviiError::GraphQL(result.errors));
+        }
+        let data = result.data.unwrap_or(Value::Null);
+        serde_json::from_value(data.clone()).map_err(|source| DeviiError::Decode { body: data.to_string(), source })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::error::DeviiError;
+    use crate::raw::RawQuery;
+    use crate::test_struct::{connect, insert_rows};
+
+    #[test]
+    fn execute_raw_query_test() {
+        let (server, client) = connect();
+        insert_rows(&server, "test_many_to_one", ["a", "b", "c"].map(|value| serde_json::json!({ "value": value })));
+
+        #[derive(serde::Serialize)]
+        struct Variables { filter: String, limit: u64 }
+
+        let document = "query first ($filter: String, $limit: Int){ rows: test_many_to_one (filter: $filter, limit: $limit){ value } }
+            mutation rename { update_test_many_to_one (id: 1, input: { value: \"renamed\" }){ value } }";
+        let raw = RawQuery::new(document)
+            .variables(Variables { filter: "id > 1".to_string(), limit: 1 }).unwrap()
+            .operation_name("first");
+        let data: serde_json::Value = client.execute_sync(&raw).unwrap();
+        assert_eq!(data, serde_json::json!({ "rows": [{ "value": "b" }] }));
+        assert_eq!(server.queries()[0]["operationName"], "first");
+
+        server.expire_tokens();
+        let renamed: HashMap<String, HashMap<String, String>> = tokio_test::block_on(client.execute(&raw.clone().operation_name("rename"))).unwrap();
+        assert_eq!(renamed["update_test_many_to_one"]["value"], "renamed");
+
+        let unnamed = RawQuery { operation_name: None, ..raw };
+        assert!(matches!(client.execute_sync::<serde_json::Value>(&unnamed), Err(DeviiError::GraphQL(_))));
+        assert!(matches!(client.execute_sync::<serde_json::Value>(&RawQuery::new(" ")), Err(DeviiError::InvalidInput(_))));
+    }
+}
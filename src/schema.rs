@@ -0,0 +1,416 @@
+//! Schema introspection. Devii exposes every table as an object type named after the table, with
+//! a `<table>Input` input object listing its writable columns. Fields of a table type are either
+//! columns, whose type is a scalar or an enum, or relations, whose type is another table:
+//!
+//! ```text
+//! test_one_to_many { id: ID!, value: String, test_many_to_one_collection: [test_many_to_one] }
+//! ```
+//!
+//! `validate` compares what a `DeviiTrait` type selects and writes against that schema, so a
+//! renamed column shows up as a `SchemaIssue` instead of a decode failure at runtime.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::devii::{DeviiClient, DeviiQueryResult, DeviiTrait, FieldKind, Operation};
+use crate::error::DeviiError;
+
+/// The tables of a Devii tenant, by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schema {
+    pub tables: BTreeMap<String, TableSchema>
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    /// The columns that can be selected.
+    pub columns: Vec<ColumnSchema>,
+    /// The columns of the `<table>Input` input object, i.e. the writable ones.
+    pub inputs: Vec<ColumnSchema>,
+    pub relations: Vec<RelationSchema>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// The GraphQL type of the column, or of its items for array columns, e.g. `Int` or `String`.
+    pub type_name: String,
+    pub nullable: bool,
+    /// Whether the column is an array.
+    pub list: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelationSchema {
+    pub name: String,
+    /// The related table.
+    pub table: String,
+    /// Whether the relation selects a list, i.e. the one side of a one to many relation.
+    pub many: bool
+}
+
+impl TableSchema {
+    pub fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn input(&self, name: &str) -> Option<&ColumnSchema> {
+        self.inputs.iter().find(|c| c.name == name)
+    }
+
+    pub fn relation(&self, name: &str) -> Option<&RelationSchema> {
+        self.relations.iter().find(|r| r.name == name)
+    }
+}
+
+/// A difference between a `DeviiTrait` type and its table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaIssue {
+    /// There is no table named `table`.
+    MissingTable { table: String },
+    /// `fetch_fields` selects a column or relation the table doesn't have.
+    MissingColumn { column: String },
+    /// A writable field has no column in the table's input type.
+    MissingInput { column: String },
+    /// A relation field doesn't match a relation of the table, or points the other way.
+    MissingRelation { relation: String },
+    /// The Rust type of a field can't hold the column's values.
+    TypeMismatch { column: String, rust_type: String, graphql_type: String },
+    /// A nullable column is read into a field that isn't an `Option`.
+    NullableColumn { column: String },
+    /// A column required on input is held in an `Option`.
+    RequiredInput { column: String }
+}
+
+impl fmt::Display for SchemaIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaIssue::MissingTable { table } => write!(f, "there is no table `{}`", table),
+            SchemaIssue::MissingColumn { column } => write!(f, "the table has no column `{}`", column),
+            SchemaIssue::MissingInput { column } => write!(f, "`{}` is not writable", column),
+            SchemaIssue::MissingRelation { relation } => write!(f, "the table has no matching relation `{}`", relation),
+            SchemaIssue::TypeMismatch { column, rust_type, graphql_type } => {
+                write!(f, "`{}` is a {} but the field is a {}", column, graphql_type, rust_type)
+            },
+            SchemaIssue::NullableColumn { column } => write!(f, "`{}` is nullable but the field isn't an Option", column),
+            SchemaIssue::RequiredInput { column } => write!(f, "`{}` is required on input but the field is an Option", column)
+        }
+    }
+}
+
+impl Schema {
+    pub fn table(&self, name: &str) -> Option<&TableSchema> {
+        self.tables.get(name)
+    }
+
+    /// The differences between `T` and its table, empty when `T` matches.
+    ///
+    /// Every name `T::fetch_fields` selects must exist on the table and every writable field on
+    /// `T::input_type`. Types and nullability are checked from `T::fields`, so types without
+    /// field metadata are only checked for missing columns.
+    pub fn validate<T: DeviiTrait + Default>(&self) -> Vec<SchemaIssue> {
+        let table_name = T::table_name();
+        let table = match self.table(&table_name) {
+            Some(table) => table,
+            None => return vec![SchemaIssue::MissingTable { table: table_name }]
+        };
+        let mut issues = vec![];
+
+        for name in top_level_selections(&T::fetch_fields()) {
+            if table.column(&name).is_none() && table.relation(&name).is_none() {
+                issues.push(SchemaIssue::MissingColumn { column: name });
+            }
+        }
+
+        let input_type = T::default().input_type();
+        let inputs = self.tables.values()
+            .find(|t| format!("{}Input", t.name) == input_type)
+            .map(|t| &t.inputs);
+        for field in T::fields() {
+            let column = field.graphql_name.to_string();
+            match field.kind {
+                FieldKind::Column | FieldKind::ReadOnly => {
+                    let schema = match table.column(&column) {
+                        Some(schema) => schema,
+                        None => continue
+                    };
+                    if !compatible(field.rust_type, schema) {
+                        issues.push(SchemaIssue::TypeMismatch {
+                            column: column.clone(),
+                            rust_type: field.rust_type.to_string(),
+                            graphql_type: schema.type_name.clone()
+                        });
+                    }
+                    if schema.nullable && !field.optional {
+                        issues.push(SchemaIssue::NullableColumn { column: column.clone() });
+                    }
+                    if field.kind == FieldKind::Column {
+                        match inputs.and_then(|inputs| inputs.iter().find(|c| c.name == column)) {
+                            None => issues.push(SchemaIssue::MissingInput { column }),
+                            Some(input) if !input.nullable && field.optional => issues.push(SchemaIssue::RequiredInput { column }),
+                            Some(_) => {}
+                        }
+                    }
+                },
+                FieldKind::HasMany { .. } | FieldKind::BelongsTo { .. } => {
+                    let many = matches!(field.kind, FieldKind::HasMany { .. });
+                    if let Some(relation) = table.relation(&column) {
+                        if relation.many != many {
+                            issues.push(SchemaIssue::MissingRelation { relation: column });
+                        }
+                    }
+                }
+            }
+        }
+        issues
+    }
+}
+
+// The names of the root selections, e.g. `id`, `string` and `rel` for
+// `{ id, label: string, rel { id } }`.
+fn top_level_selections(fields: &str) -> Vec<String> {
+    let fields = fields.trim();
+    let inner = fields.strip_prefix('{').and_then(|f| f.strip_suffix('}')).unwrap_or(fields);
+    let mut names = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+
+    for c in inner.chars().chain(std::iter::once(',')) {
+        match c {
+            '{' | '(' => depth += 1,
+            '}' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                // An alias is followed by the name it selects.
+                let name = current.rsplit(':').next().unwrap_or_default().trim().to_string();
+                if !name.is_empty() {
+                    names.push(name);
+                }
+                current.clear();
+            },
+            _ if depth == 0 => current.push(c),
+            _ => {}
+        }
+    }
+    names
+}
+
+// Only the scalars whose Rust counterpart is clear are checked; custom scalars such as
+// `DateTime` or `JSON` and Rust types like `serde_json::Value` match anything.
+fn compatible(rust_type: &str, column: &ColumnSchema) -> bool {
+    let (rust_type, rust_list) = match rust_type.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+        Some(item) => (item, true),
+        None => (rust_type, false)
+    };
+    let base = rust_type.rsplit("::").next().unwrap_or(rust_type);
+
+    let integer = matches!(base, "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16" | "i32" | "i64" | "i128" | "isize");
+    let float = matches!(base, "f32" | "f64");
+    let text = matches!(base, "String" | "char" | "str" | "Uuid");
+    let boolean = base == "bool";
+    if !(integer || float || text || boolean) {
+        return true;
+    }
+    if rust_list != column.list {
+        return false;
+    }
+
+    match column.type_name.as_str() {
+        "Int" | "BigInt" | "SmallInt" => integer || float,
+        "Float" | "Decimal" | "Numeric" => float || text,
+        "String" => text,
+        // Devii hands out ids as strings, which the id helpers parse back into numbers.
+        "ID" => text || integer,
+        "Boolean" => boolean,
+        _ => true
+    }
+}
+
+// What the introspection query returns, the parts of it `Schema` is built from.
+#[derive(Deserialize, Debug)]
+struct IntrospectionSchema {
+    types: Vec<IntrospectionType>
+}
+
+#[derive(Deserialize, Debug)]
+struct IntrospectionType {
+    kind: String,
+    name: Option<String>,
+    #[serde(default)]
+    fields: Option<Vec<IntrospectionField>>,
+    #[serde(default, rename = "inputFields")]
+    input_fields: Option<Vec<IntrospectionField>>
+}
+
+#[derive(Deserialize, Debug)]
+struct IntrospectionField {
+    name: String,
+    #[serde(rename = "type")]
+    ty: TypeRef
+}
+
+#[derive(Deserialize, Debug)]
+struct TypeRef {
+    kind: String,
+    name: Option<String>,
+    #[serde(default, rename = "ofType")]
+    of_type: Option<Box<TypeRef>>
+}
+
+impl TypeRef {
+    // The named type under the `NON_NULL` and `LIST` wrappers, whether the outermost type is
+    // nullable and whether there is a list.
+    fn named_type(&self) -> (&TypeRef, bool, bool) {
+        let nullable = self.kind != "NON_NULL";
+        let mut list = false;
+        let mut ty = self;
+        while let Some(inner) = &ty.of_type {
+            list |= ty.kind == "LIST";
+            ty = inner;
+        }
+        (ty, nullable, list)
+    }
+}
+
+// Three levels of `ofType` cover the deepest wrapping Devii uses, `[T!]!`.
+const INTROSPECTION_QUERY: &str = "query introspect {
+        __schema {
+          types {
+            kind name
+            fields { name type { kind name ofType { kind name ofType { kind name ofType { kind name } } } } }
+            inputFields { name type { kind name ofType { kind name ofType { kind name ofType { kind name } } } } }
+          }
+        }
+      }";
+
+#[derive(Serialize, Debug)]
+struct IntrospectionQuery {
+    query: &'static str
+}
+
+impl From<IntrospectionSchema> for Schema {
+    fn from(introspection: IntrospectionSchema) -> Self {
+        let inputs: BTreeMap<&str, &Vec<IntrospectionField>> = introspection.types.iter()
+            .filter(|t| t.kind == "INPUT_OBJECT")
+            .filter_map(|t| Some((t.name.as_deref()?, t.input_fields.as_ref()?)))
+            .collect();
+        // Tables are the object types that have an input type.
+        let is_table = |name: &str| inputs.contains_key(format!("{}Input", name).as_str());
+
+        let mut tables = BTreeMap::new();
+        for ty in introspection.types.iter().filter(|t| t.kind == "OBJECT") {
+            let name = match &ty.name {
+                Some(name) if is_table(name) => name.clone(),
+                _ => continue
+            };
+            let mut table = TableSchema { name: name.clone(), ..Default::default() };
+
+            for field in ty.fields.iter().flatten() {
+                let (named, nullable, list) = field.ty.named_type();
+                let type_name = named.name.clone().unwrap_or_default();
+                match named.kind.as_str() {
+                    "SCALAR" | "ENUM" => table.columns.push(ColumnSchema { name: field.name.clone(), type_name, nullable, list }),
+                    "OBJECT" if is_table(&type_name) => table.relations.push(RelationSchema { name: field.name.clone(), table: type_name, many: list }),
+                    _ => {}
+                }
+            }
+            for field in inputs[format!("{}Input", name).as_str()] {
+                let (named, nullable, list) = field.ty.named_type();
+                table.inputs.push(ColumnSchema {
+                    name: field.name.clone(),
+                    type_name: named.name.clone().unwrap_or_default(),
+                    nullable,
+                    list
+                });
+            }
+            tables.insert(name, table);
+        }
+        Schema { tables }
+    }
+}
+
+impl DeviiClient {
+    /// Runs GraphQL introspection and returns the tenant's tables.
+    pub async fn introspect(&self) -> Result<Schema, DeviiError> {
+        self.run(introspect_operation()?).await
+    }
+
+    pub fn introspect_sync(&self) -> Result<Schema, DeviiError> {
+        self.run_sync(introspect_operation()?)
+    }
+
+    /// Introspects the schema and compares `T` against its table, see `Schema::validate`.
+    /// Validating several types is cheaper with one `introspect` and `Schema::validate`.
+    pub async fn validate<T: DeviiTrait + Default>(&self) -> Result<Vec<SchemaIssue>, DeviiError> {
+        Ok(self.introspect().await?.validate::<T>())
+    }
+
+    pub fn validate_sync<T: DeviiTrait + Default>(&self) -> Result<Vec<SchemaIssue>, DeviiError> {
+        Ok(self.introspect_sync()?.validate::<T>())
+    }
+}
+
+fn introspect_operation() -> Result<Operation<DeviiQueryResult<IntrospectionSchema>, Schema>, DeviiError> {
+    Operation::new(&IntrospectionQuery { query: INTROSPECTION_QUERY }, |mut result: DeviiQueryResult<IntrospectionSchema>| {
+        Ok(result.take("__schema")?.into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviiError;
+    use crate::schema::SchemaIssue;
+    use crate::test_struct::{connect, TestManyToOne, TestOneToMany, TestStruct};
+
+    #[test]
+    fn introspect_validate_test() {
+        use serde_json::{json, Value};
+        fn scalar(name: &str) -> Value { json!({ "kind": "SCALAR", "name": name, "ofType": null }) }
+        fn object(name: &str) -> Value { json!({ "kind": "OBJECT", "name": name, "ofType": null }) }
+        fn required(ty: Value) -> Value { json!({ "kind": "NON_NULL", "name": null, "ofType": ty }) }
+        fn list(ty: Value) -> Value { json!({ "kind": "LIST", "name": null, "ofType": ty }) }
+        fn field(name: &str, ty: Value) -> Value { json!({ "name": name, "type": ty }) }
+
+        let (server, client) = connect();
+        assert!(matches!(client.introspect_sync(), Err(DeviiError::GraphQL(_))));
+
+        server.set_schema(json!({ "types": [
+            { "kind": "OBJECT", "name": "test_one_to_many", "fields": [
+                field("id", required(scalar("ID"))),
+                field("value", required(scalar("String"))),
+                field("test_many_to_one_collection", list(required(object("test_many_to_one"))))
+            ]},
+            { "kind": "INPUT_OBJECT", "name": "test_one_to_manyInput", "inputFields": [
+                field("id", scalar("ID")),
+                field("value", required(scalar("String")))
+            ]},
+            { "kind": "OBJECT", "name": "test_many_to_one", "fields": [
+                field("id", required(scalar("ID"))),
+                field("value", scalar("String")),
+                field("test_one_to_many_id", scalar("String")),
+                field("test_one_to_many", object("test_one_to_many"))
+            ]},
+            { "kind": "INPUT_OBJECT", "name": "test_many_to_oneInput", "inputFields": [
+                field("id", scalar("ID")),
+                field("value", scalar("String"))
+            ]},
+            { "kind": "OBJECT", "name": "Query", "fields": [field("test_one_to_many", list(object("test_one_to_many")))] },
+            { "kind": "SCALAR", "name": "String" }
+        ]}));
+
+        let schema = tokio_test::block_on(client.introspect()).unwrap();
+        assert_eq!(schema.tables.keys().collect::<Vec<_>>(), vec!["test_many_to_one", "test_one_to_many"]);
+        let parent = schema.table("test_one_to_many").unwrap();
+        assert!(parent.relation("test_many_to_one_collection").unwrap().many);
+        assert!(!parent.column("value").unwrap().nullable);
+
+        assert_eq!(schema.validate::<TestOneToMany>(), vec![]);
+        assert_eq!(client.validate_sync::<TestManyToOne>().unwrap(), vec![
+            SchemaIssue::NullableColumn { column: "value".to_string() },
+            SchemaIssue::TypeMismatch { column: "test_one_to_many_id".to_string(), rust_type: "u64".to_string(), graphql_type: "String".to_string() },
+            SchemaIssue::MissingInput { column: "test_one_to_many_id".to_string() }
+        ]);
+        assert_eq!(schema.validate::<TestStruct>(), vec![SchemaIssue::MissingTable { table: "test_struct".to_string() }]);
+    }
+}
@@ -2,14 +2,33 @@ use serde::{Deserialize, Serialize};
 use serde::de::{Deserializer};
 use named_type_derive::*;
 use named_type::NamedType;
-use serde_json::Value;
 
-use crate::devii::{DeviiTrait};
+use crate::Devii;
+#[cfg(test)]
+use crate::devii::DeviiClient;
+#[cfg(test)]
+use crate::testing::MockDevii;
 
-#[derive(Serialize, Deserialize, Debug, NamedType, Default)]
+// A `MockDevii` and a client logged in to it, the preamble of the client tests.
+#[cfg(test)]
+pub fn connect() -> (MockDevii, DeviiClient) {
+    let server = MockDevii::start();
+    let client = DeviiClient::connect_sync(server.options()).unwrap();
+    (server, client)
+}
+
+// Stores `rows` in `table` and returns their ids.
+#[cfg(test)]
+pub fn insert_rows(server: &MockDevii, table: &str, rows: impl IntoIterator<Item = serde_json::Value>) -> Vec<u64> {
+    rows.into_iter().map(|row| server.insert_row(table, row).as_u64().unwrap()).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
 pub struct TestStruct {
     #[serde(deserialize_with = "deserialize_u64_or_string")]
     #[serde(skip_serializing)]
+    #[devii(read_only)]
     pub id: Option<u64>,
     pub string: String, 
     pub _char: char,
@@ -65,34 +84,45 @@ impl TestStruct{
     }
 }
 
-impl DeviiTrait for TestStruct {
-    fn fetch_fields() -> String {
-        format!("{{ id, string, _char, _u8, _u16, _u32, _i8, _i16, _i32, _i64, _f32, _f64 }}")
-    }
-    fn insert_query(&self, param: String) -> String{
-        format!("create_test_struct (input: ${} ){{ id }}", param)
-    }
-    fn input_type(&self) -> String {
-        "test_structInput".to_string()
-    }
-    fn graphql_inputs(&self) -> serde_json::Value {
-        serde_json::to_value(&self).unwrap()
-    }
-    fn delete_input(&self) -> String {
-        format!("id: {}", self.id.unwrap())
-    }
+
+// Maps onto the `test_struct` table under different field names.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate", table = "test_struct")]
+pub struct TestRenamed {
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    #[devii(read_only)]
+    pub id: Option<u64>,
+    #[devii(rename = "string")]
+    pub label: String,
+    #[serde(skip)]
+    #[devii(skip)]
+    pub cached: Option<String>,
 }
 
+// Serialized in camelCase, which is also the case of its columns.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[serde(rename_all = "camelCase")]
+#[devii(crate = "crate", table = "test_camel_case")]
+pub struct TestCamelCase {
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub id: Option<u64>,
+    pub display_name: String,
+    #[devii(rename = "sort_key")]
+    pub sort_order: u32,
+}
 
 // Credit : https://noyez.gitlab.io/post/2018-08-28-serilize-this-or-that-into-u64/
 #[derive(Deserialize)]
 #[serde(untagged)]
-enum StringOrU64 { U64(u64), Str(String) }
+enum StringOrU64 { U64(u64), Str(String), Null(()) }
 pub fn deserialize_u64_or_string<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
     where D: Deserializer<'de>
 {
     match StringOrU64::deserialize(deserializer)? {
         StringOrU64::U64(v) => { Ok(Some(v)) }
+        StringOrU64::Null(()) => { Ok(None) }
         StringOrU64::Str(v) => {
             let res = v.parse::<u64>();
             if let Ok(r) = res {
@@ -104,77 +134,105 @@ pub fn deserialize_u64_or_string<'de, D>(deserializer: D) -> Result<Option<u64>,
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, NamedType, Default)]
-pub struct TestOneToMany {
+// Keyed by two columns instead of a serial id.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii, Clone, PartialEq)]
+#[devii(crate = "crate")]
+pub struct TestComposite {
+    #[devii(id)]
+    pub hash: String,
+    #[devii(id)]
+    pub index: u32,
+    pub value: String
+}
+
+// Points at its parent through `owner` rather than a `*_id` column.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestOwned {
     #[serde(deserialize_with = "deserialize_u64_or_string")]
     pub id: Option<u64>,
     pub value: String,
-    pub test_many_to_one_collection : Option<Vec<TestManyToOne>>
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub owner: Option<u64>,
+    #[devii(belongs_to, fk = "owner")]
+    pub parent: Option<TestOneToMany>
 }
 
+// Keyed by a uuid the caller picks.
+#[cfg(feature = "uuid")]
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestUuid {
+    pub id: Option<uuid::Uuid>,
+    pub value: String
+}
 
-impl DeviiTrait for TestOneToMany {
-    fn fetch_fields() -> String {
-        format!("{{ id, value, test_many_to_one_collection {} }}", TestManyToOne::fetch_fields())
-    }
-    fn insert_query(&self, param: String) -> String{
-        format!("create_test_one_to_many (input: ${} ){{ id }}", param)
-    }
-    fn input_type(&self) -> String {
-        "test_one_to_manyInput".to_string()
-    }
-    fn graphql_inputs(&self) -> serde_json::Value {
-        let value = serde_json::to_value(&self).unwrap();
-
-        match value {
-            Value::Object(mut map) => {
-                map.remove_entry("test_many_to_one_collection");
-                return Value::Object(map);
-            },
-            _ => panic!("object wasn't a map!"),
-        }
-    }
-    fn delete_input(&self) -> String {
-        format!("id: {}", self.id.unwrap())
-    }
+// Keyed by a string the caller picks.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestSlug {
+    #[devii(id)]
+    pub slug: String,
+    pub value: String
+}
+
+// Relates to itself both ways, so selecting it must not recurse.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestCategory {
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub id: Option<u64>,
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub parent_id: Option<u64>,
+    #[devii(has_many, fk = "parent_id")]
+    pub children: Option<Vec<TestCategory>>,
+    #[devii(belongs_to)]
+    pub parent: Option<Box<TestCategory>>
+}
+
+// JSONB and Postgres array columns, which are columns even though they serialize to objects
+// and arrays.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestJsonColumns {
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub id: Option<u64>,
+    pub tags: Vec<String>,
+    pub scores: Option<Vec<i32>>,
+    pub metadata: serde_json::Value
+}
+
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
+pub struct TestOneToMany {
+    #[serde(deserialize_with = "deserialize_u64_or_string")]
+    pub id: Option<u64>,
+    pub value: String,
+    #[devii(has_many)]
+    pub test_many_to_one_collection : Option<Vec<TestManyToOne>>
 }
 
-#[derive(Serialize, Deserialize, Debug, NamedType, Default)]
+
+#[derive(Serialize, Deserialize, Debug, NamedType, Default, Devii)]
+#[devii(crate = "crate")]
 pub struct TestManyToOne {
     #[serde(deserialize_with = "deserialize_u64_or_string")]
     // #[serde(skip_serializing)]
     pub id: Option<u64>,
+    pub value: String,
     #[serde(deserialize_with = "deserialize_u64_or_string")]
     pub test_one_to_many_id: Option<u64>,
-    pub test_one_to_many: Option<TestOneToMany>,
-    pub value: String
+    #[devii(belongs_to, fk = "test_one_to_many_id")]
+    pub test_one_to_many: Option<TestOneToMany>
 }
 
-impl DeviiTrait for TestManyToOne {
-    fn fetch_fields() -> String {
-        format!("{{ id, value, test_one_to_many_id, test_one_to_many {{ id, value }} }}")
-    }
-    fn insert_query(&self, param: String) -> String{
-        format!("create_test_many_to_one (input: ${} ){{ id }}", param)
-    }
-    fn input_type(&self) -> String {
-        "test_many_to_oneInput".to_string()
-    }
-    fn graphql_inputs(&self) -> serde_json::Value {
-        let value = serde_json::to_value(&self).unwrap();
-
-        match value {
-            Value::Object(mut map) => {
-                map.remove_entry("test_one_to_many");
-                return Value::Object(map);
-            },
-            _ => panic!("object wasn't a map!"),
-        }
-    }
-    fn delete_input(&self) -> String {
-        format!("id: {}", self.id.unwrap())
-    }
-}
 
 impl TestOneToMany {
     #[allow(dead_code)]
@@ -0,0 +1,353 @@
+//! Evaluates the SQL-like `filter` argument of Devii table queries against stored rows, e.g.
+//! `id = 5 and (value like 'Hello%' or parent_id is null)`.
+
+use std::cmp::Ordering;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    // Never a keyword, unlike `Ident`.
+    QuotedIdent(String),
+    Literal(Value),
+    Op(&'static str),
+    Open,
+    Close,
+    Comma
+}
+
+#[derive(Debug)]
+enum Expr {
+    Constant(bool),
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare { column: String, op: &'static str, value: Value },
+    In { column: String, values: Vec<Value> },
+    IsNull { column: String },
+    Between { column: String, low: Value, high: Value },
+    Like { column: String, pattern: String, case_insensitive: bool }
+}
+
+/// Whether `row` matches `filter`. An empty filter matches every row.
+pub(crate) fn matches(filter: &str, row: &Map<String, Value>) -> Result<bool, String> {
+    if filter.trim().is_empty() {
+        return Ok(true);
+    }
+    let mut parser = Parser { tokens: tokenize(filter)?, position: 0 };
+    let expr = parser.or()?;
+
+    if parser.position < parser.tokens.len() {
+        return Err(format!("Unexpected {:?} in filter", parser.tokens[parser.position]));
+    }
+    Ok(evaluate(&expr, row))
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => { tokens.push(Token::Open); i += 1; },
+            ')' => { tokens.push(Token::Close); i += 1; },
+            ',' => { tokens.push(Token::Comma); i += 1; },
+            '=' => { tokens.push(Token::Op("=")); i += 1; },
+            '!' if chars.get(i + 1) == Some(&'=') => { tokens.push(Token::Op("!=")); i += 2; },
+            '<' => {
+                match chars.get(i + 1) {
+                    Some('=') => { tokens.push(Token::Op("<=")); i += 2; },
+                    Some('>') => { tokens.push(Token::Op("!=")); i += 2; },
+                    _ => { tokens.push(Token::Op("<")); i += 1; }
+                }
+            },
+            '>' => {
+                if chars.get(i + 1) == Some(&'=') {
+                    tokens.push(Token::Op(">="));
+                    i += 2;
+                } else {
+                    tokens.push(Token::Op(">"));
+                    i += 1;
+                }
+            },
+            '\'' => {
+                // Quotes inside strings are doubled: 'it''s'.
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("Unterminated string in filter".to_string()),
+                        Some('\'') if chars.get(i + 1) == Some(&'\'') => { value.push('\''); i += 2; },
+                        Some('\'') => { i += 1; break; },
+                        Some(other) => { value.push(*other); i += 1; }
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(value)));
+            },
+            '"' => {
+                // Quoted identifiers double their quotes too: "my ""col""".
+                let mut name = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("Unterminated identifier in filter".to_string()),
+                        Some('"') if chars.get(i + 1) == Some(&'"') => { name.push('"'); i += 2; },
+                        Some('"') => { i += 1; break; },
+                        Some(other) => { name.push(*other); i += 1; }
+                    }
+                }
+                tokens.push(Token::QuotedIdent(name));
+            },
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || matches!(chars[i], '.' | 'e' | 'E')) {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let value: Value = serde_json::from_str(&text).map_err(|_| format!("Invalid number {} in filter", text))?;
+                tokens.push(Token::Literal(value));
+            },
+            c if c == '_' || c.is_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i] == '.' || chars[i].is_alphanumeric()) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.to_lowercase().as_str() {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Ident(word)
+                });
+            },
+            other => return Err(format!("Unexpected character {:?} in filter", other))
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Ident(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("Expected `{}` in filter", keyword))
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("Unexpected end of filter")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        match self.next()? {
+            Token::Literal(value) => Ok(value),
+            other => Err(format!("Expected a value in filter, found {:?}", other))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr, String> {
+        let column = match self.next()? {
+            Token::Open => {
+                let expr = self.or()?;
+                match self.next()? {
+                    Token::Close => return Ok(expr),
+                    other => return Err(format!("Expected ')' in filter, found {:?}", other))
+                }
+            },
+            Token::Literal(Value::Bool(constant)) => return Ok(Expr::Constant(constant)),
+            Token::Ident(column) | Token::QuotedIdent(column) => column,
+            other => return Err(format!("Expected a column in filter, found {:?}", other))
+        };
+
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            if self.next()? != Token::Literal(Value::Null) {
+                return Err("Expected `null` after `is` in filter".to_string());
+            }
+            return Ok(negate(Expr::IsNull { column }, negated));
+        }
+
+        let negated = self.eat_keyword("not");
+
+        if self.eat_keyword("in") {
+            if self.next()? != Token::Open {
+                return Err("Expected '(' after `in` in filter".to_string());
+            }
+            let mut values = vec![];
+            loop {
+                values.push(self.literal()?);
+                match self.next()? {
+                    Token::Comma => continue,
+                    Token::Close => break,
+                    other => return Err(format!("Expected ',' or ')' in filter, found {:?}", other))
+                }
+            }
+            return Ok(negate(Expr::In { column, values }, negated));
+        }
+        if self.eat_keyword("between") {
+            let low = self.literal()?;
+            self.expect_keyword("and")?;
+            let high = self.literal()?;
+            return Ok(negate(Expr::Between { column, low, high }, negated));
+        }
+        for (keyword, case_insensitive) in [("like", false), ("ilike", true)] {
+            if self.eat_keyword(keyword) {
+                let pattern = match self.literal()? {
+                    Value::String(pattern) => pattern,
+                    other => return Err(format!("Expected a pattern after `{}`, found {}", keyword, other))
+                };
+                return Ok(negate(Expr::Like { column, pattern, case_insensitive }, negated));
+            }
+        }
+        if negated {
+            return Err("Expected `in`, `between` or `like` after `not` in filter".to_string());
+        }
+
+        match self.next()? {
+            Token::Op(op) => Ok(Expr::Compare { column, op, value: self.literal()? }),
+            other => Err(format!("Expected an operator in filter, found {:?}", other))
+        }
+    }
+}
+
+fn negate(expr: Expr, negated: bool) -> Expr {
+    if negated {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    }
+}
+
+fn evaluate(expr: &Expr, row: &Map<String, Value>) -> bool {
+    let column = |name: &String| row.get(name).unwrap_or(&Value::Null);
+
+    match expr {
+        Expr::Constant(constant) => *constant,
+        Expr::Or(a, b) => evaluate(a, row) || evaluate(b, row),
+        Expr::And(a, b) => evaluate(a, row) && evaluate(b, row),
+        Expr::Not(e) => !evaluate(e, row),
+        Expr::IsNull { column: c } => column(c).is_null(),
+        Expr::In { column: c, values } => values.iter().any(|v| compare(column(c), v) == Some(Ordering::Equal)),
+        Expr::Between { column: c, low, high } => {
+            let value = column(c);
+            matches!(compare(value, low), Some(Ordering::Greater | Ordering::Equal))
+                && matches!(compare(value, high), Some(Ordering::Less | Ordering::Equal))
+        },
+        Expr::Like { column: c, pattern, case_insensitive } => match column(c) {
+            Value::String(s) if *case_insensitive => like(&s.to_lowercase(), &pattern.to_lowercase()),
+            Value::String(s) => like(s, pattern),
+            _ => false
+        },
+        Expr::Compare { column: c, op, value } => {
+            let ordering = compare(column(c), value);
+            match *op {
+                "=" => ordering == Some(Ordering::Equal),
+                "!=" => matches!(ordering, Some(Ordering::Less | Ordering::Greater)),
+                "<" => ordering == Some(Ordering::Less),
+                "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                ">" => ordering == Some(Ordering::Greater),
+                ">=" => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                _ => false
+            }
+        }
+    }
+}
+
+/// Compares two stored values like Postgres would after casting the literal to the column type.
+/// `None` means they aren't comparable, e.g. either is null.
+pub(crate) fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::Number(x), Value::String(y)) => x.as_f64()?.partial_cmp(&y.parse::<f64>().ok()?),
+        (Value::String(x), Value::Number(y)) => x.parse::<f64>().ok()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        _ => None
+    }
+}
+
+// `%` matches any run of characters and `_` exactly one.
+fn like(value: &str, pattern: &str) -> bool {
+    fn matches_from(value: &[char], pattern: &[char]) -> bool {
+        match pattern.split_first() {
+            None => value.is_empty(),
+            Some(('%', rest)) => (0..=value.len()).any(|i| matches_from(&value[i..], rest)),
+            Some(('_', rest)) => !value.is_empty() && matches_from(&value[1..], rest),
+            Some((c, rest)) => value.first() == Some(c) && matches_from(&value[1..], rest)
+        }
+    }
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    matches_from(&value, &pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::testing::filter::matches;
+
+    #[test]
+    fn filter_matches_test() {
+        let row = json!({ "id": 5, "value": "Hello World", "parent_id": null, "score": 2.5 });
+        let row = row.as_object().unwrap();
+
+        assert!(matches("id = 5", row).unwrap());
+        assert!(matches("id = '5' and value like 'Hello%'", row).unwrap());
+        assert!(matches("id in (1, 5) and parent_id is null", row).unwrap());
+        assert!(matches("not (id < 5) and score between 2 and 3", row).unwrap());
+        assert!(matches("value ilike '%world' or id = 1", row).unwrap());
+        assert!(!matches("parent_id = 1 or parent_id is not null", row).unwrap());
+        assert!(!matches("id not in (5)", row).unwrap());
+        assert!(matches("\"id\" = 5 and not false", row).unwrap());
+        assert!(matches("id =", row).is_err());
+    }
+}
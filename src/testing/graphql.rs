@@ -0,0 +1,351 @@
+//! A parser for the subset of GraphQL the client emits: one query or mutation with variable
+//! definitions, aliases, arguments and nested selections. Variables are substituted while parsing.
+
+use serde_json::{Map, Number, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OperationKind {
+    Query,
+    Mutation
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Operation {
+    pub kind: OperationKind,
+    pub selection: Vec<Field>
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Map<String, Value>,
+    pub selection: Vec<Field>
+}
+
+impl Field {
+    /// The key of the field in the response.
+    pub fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Number(String),
+    Str(String)
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // Commas are insignificant in GraphQL.
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            },
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '!' | '$' | '=' | '@' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            },
+            '.' => {
+                if chars[i..].starts_with(&['.', '.', '.']) {
+                    tokens.push(Token::Spread);
+                    i += 3;
+                } else {
+                    return Err(format!("Unexpected character '.' at {}", i));
+                }
+            },
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("Unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = chars.get(i + 1).copied().ok_or("Unterminated string")?;
+                            match escaped {
+                                'n' => value.push('\n'),
+                                't' => value.push('\t'),
+                                'r' => value.push('\r'),
+                                'b' => value.push('\u{8}'),
+                                'f' => value.push('\u{c}'),
+                                'u' => {
+                                    let hex: String = chars.get(i + 2..i + 6).ok_or("Invalid unicode escape")?.iter().collect();
+                                    let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                                    value.push(char::from_u32(code).ok_or("Invalid unicode escape")?);
+                                    i += 4;
+                                },
+                                other => value.push(other)
+                            }
+                            i += 2;
+                        },
+                        Some(other) => {
+                            value.push(*other);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                tokens.push(Token::Str(value));
+            },
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || matches!(chars[i], '.' | 'e' | 'E' | '+' | '-')) {
+                    i += 1;
+                }
+                tokens.push(Token::Number(chars[start..i].iter().collect()));
+            },
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            },
+            other => return Err(format!("Unexpected character {:?} at {}", other, i))
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    variables: &'a Map<String, Value>
+}
+
+// Picks the operation named `operation_name`, which is required when the document has several.
+pub(crate) fn parse(source: &str, variables: &Map<String, Value>, operation_name: Option<&str>) -> Result<Operation, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        variables
+    };
+    let mut operations = vec![parser.operation()?];
+    while parser.position < parser.tokens.len() {
+        operations.push(parser.operation()?);
+    }
+
+    match operation_name {
+        Some(name) => operations.into_iter()
+            .find(|(n, _)| n.as_deref() == Some(name))
+            .map(|(_, operation)| operation)
+            .ok_or_else(|| format!("Unknown operation named \"{}\".", name)),
+        None if operations.len() == 1 => Ok(operations.remove(0).1),
+        None => Err("Must provide operation name if query contains multiple operations.".to_string())
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("Unexpected end of document")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(c) if c == punct => Ok(()),
+            other => Err(format!("Expected '{}', found {:?}", punct, other))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            other => Err(format!("Expected a name, found {:?}", other))
+        }
+    }
+
+    fn operation(&mut self) -> Result<(Option<String>, Operation), String> {
+        let kind = match self.peek() {
+            Some(Token::Punct('{')) => OperationKind::Query,
+            Some(Token::Name(name)) if name == "query" => OperationKind::Query,
+            Some(Token::Name(name)) if name == "mutation" => OperationKind::Mutation,
+            other => return Err(format!("Unsupported operation {:?}", other))
+        };
+
+        let mut name = None;
+        if kind == OperationKind::Mutation || self.peek() != Some(&Token::Punct('{')) {
+            self.position += 1;
+            if let Some(Token::Name(n)) = self.peek() {
+                name = Some(n.clone());
+                self.position += 1;
+            }
+            if self.eat('(') {
+                self.variable_definitions()?;
+            }
+        }
+
+        Ok((name, Operation {
+            kind,
+            selection: self.selection_set()?
+        }))
+    }
+
+    // Types and defaults are skipped, values always come from the request's variables.
+    fn variable_definitions(&mut self) -> Result<(), String> {
+        while !self.eat(')') {
+            self.expect('$')?;
+            self.name()?;
+            self.expect(':')?;
+            self.skip_type()?;
+            if self.eat('=') {
+                self.value()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_type(&mut self) -> Result<(), String> {
+        if self.eat('[') {
+            self.skip_type()?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Field>, String> {
+        self.expect('{')?;
+        let mut fields = vec![];
+
+        while !self.eat('}') {
+            if self.peek() == Some(&Token::Spread) {
+                return Err("Fragments are not supported".to_string());
+            }
+            fields.push(self.field()?);
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field, String> {
+        let mut name = self.name()?;
+        let mut alias = None;
+
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+
+        let mut arguments = Map::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let argument = self.name()?;
+                self.expect(':')?;
+                arguments.insert(argument, self.value()?);
+            }
+        }
+
+        let selection = if self.peek() == Some(&Token::Punct('{')) {
+            self.selection_set()?
+        } else {
+            vec![]
+        };
+
+        Ok(Field { alias, name, arguments, selection })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next()? {
+            Token::Punct('$') => {
+                let name = self.name()?;
+                Ok(self.variables.get(&name).cloned().unwrap_or(Value::Null))
+            },
+            Token::Punct('[') => {
+                let mut values = vec![];
+                while !self.eat(']') {
+                    values.push(self.value()?);
+                }
+                Ok(Value::Array(values))
+            },
+            Token::Punct('{') => {
+                let mut map = Map::new();
+                while !self.eat('}') {
+                    let key = self.name()?;
+                    self.expect(':')?;
+                    map.insert(key, self.value()?);
+                }
+                Ok(Value::Object(map))
+            },
+            Token::Number(n) => {
+                if let Ok(i) = n.parse::<i64>() {
+                    return Ok(Value::from(i));
+                }
+                if let Ok(u) = n.parse::<u64>() {
+                    return Ok(Value::from(u));
+                }
+                n.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number).ok_or(format!("Invalid number {}", n))
+            },
+            Token::Str(s) => Ok(Value::String(s)),
+            Token::Name(name) => Ok(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // Enum values are passed around as strings.
+                _ => Value::String(name)
+            }),
+            other => Err(format!("Unexpected {:?} in value", other))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+    use crate::testing::graphql::{parse, OperationKind};
+
+    #[test]
+    fn parse_aliased_mutation_test() {
+        let variables = json!({ "input_0": { "value": "a" } });
+        let operation = parse("mutation insert ($input_0: test_structInput){
+            insert_0: create_test_struct (input: $input_0 ){ id }
+        }", variables.as_object().unwrap(), None).unwrap();
+
+        assert_eq!(operation.kind, OperationKind::Mutation);
+        assert_eq!(operation.selection[0].key(), "insert_0");
+        assert_eq!(operation.selection[0].name, "create_test_struct");
+        assert_eq!(operation.selection[0].arguments["input"], json!({ "value": "a" }));
+        assert_eq!(operation.selection[0].selection[0].name, "id");
+    }
+
+    #[test]
+    fn parse_literal_arguments_test() {
+        let operation = parse("{ test_struct (limit: 5, ordering: [\"id desc\"], filter: \"id = 1\") { id, label: string } }", &Map::new(), None).unwrap();
+        let field = &operation.selection[0];
+
+        assert_eq!(operation.kind, OperationKind::Query);
+        assert_eq!(field.arguments["limit"], json!(5));
+        assert_eq!(field.arguments["ordering"], json!(["id desc"]));
+        assert_eq!(field.selection[1].key(), "label");
+        assert_eq!(field.selection[1].name, "string");
+    }
+}
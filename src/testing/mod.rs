@@ -0,0 +1,297 @@
+//! An in-process stand-in for a Devii tenant, so code using `DeviiClient` can be tested offline.
+//!
+//! `MockDevii::start` serves, on a random local port:
+//!
+//! - `POST /auth`, which accepts `MockDevii::LOGIN`/`MockDevii::PASSWORD` for tenant
+//!   `MockDevii::TENANT_ID` and answers with the same routes Devii does,
+//! - `GET /auth`, which exchanges a refresh token for a new access token,
+//! - `POST /tenant<id>/query`, which runs the GraphQL the client emits (`create_`, `update_` and
+//!   `delete_` mutations and filtered, ordered and paginated table queries with nested
+//!   relations) against in-memory tables.
+//!
+//! Tables are schemaless: `create_x` creates table `x` on first use and every input key becomes
+//! a column. Call `MockDevii::expire_tokens` to make Devii answer the next query with
+//! `Token expired.`, and `MockDevii::set_schema` to answer introspection queries.
+//!
+//! Only available with the `test-util` feature.
+
+mod filter;
+mod graphql;
+mod store;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Map, Value};
+
+use crate::devii::DeviiClientOptions;
+use crate::testing::store::Store;
+
+#[derive(Debug, Default)]
+struct MockState {
+    store: Store,
+    next_token: u64,
+    // Issued access tokens and whether they have expired.
+    access_tokens: HashMap<String, bool>,
+    refresh_tokens: HashSet<String>,
+    refreshes: usize,
+    queries: Vec<Value>
+}
+
+impl MockState {
+    fn issue_tokens(&mut self) -> (String, String) {
+        self.next_token += 1;
+        let access_token = format!("access-{}", self.next_token);
+        let refresh_token = format!("refresh-{}", self.next_token);
+
+        self.access_tokens.insert(access_token.clone(), false);
+        self.refresh_tokens.insert(refresh_token.clone());
+        (access_token, refresh_token)
+    }
+}
+
+/// A local HTTP server answering like a Devii tenant. It stops when dropped.
+#[derive(Debug)]
+pub struct MockDevii {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Arc<AtomicBool>
+}
+
+impl MockDevii {
+    pub const LOGIN: &'static str = "devii";
+    pub const PASSWORD: &'static str = "devii";
+    pub const TENANT_ID: u32 = 13;
+
+    /// Binds a random local port and starts serving on a background thread.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("MockDevii failed to bind a local port");
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let server = Server { base: format!("http://{}", address), state: state.clone() };
+        let stop = shutdown.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let server = server.clone();
+                    thread::spawn(move || server.serve(stream));
+                }
+            }
+        });
+
+        MockDevii { address, state, shutdown }
+    }
+
+    /// The url to pass as Devii's base url, e.g. `http://127.0.0.1:41234`.
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Options that connect a `DeviiClient` to this server.
+    pub fn options(&self) -> DeviiClientOptions {
+        DeviiClientOptions::new(Self::LOGIN.to_string(), Self::PASSWORD.to_string(), self.base_url(), Self::TENANT_ID)
+    }
+
+    /// Expires every access token handed out so far. Refresh tokens stay valid.
+    pub fn expire_tokens(&self) {
+        for expired in self.state.lock().unwrap().access_tokens.values_mut() {
+            *expired = true;
+        }
+    }
+
+    /// How many times a refresh token was exchanged for a new access token.
+    pub fn refreshes(&self) -> usize {
+        self.state.lock().unwrap().refreshes
+    }
+
+    /// The rows currently stored in `table`, in insertion order.
+    pub fn rows(&self, table: &str) -> Vec<Value> {
+        self.state.lock().unwrap().store.rows(table).into_iter().map(Value::Object).collect()
+    }
+
+    /// Answers introspection queries with `schema`, the value of `__schema` in the response.
+    /// Tables are schemaless, so introspection fails until this is called.
+    pub fn set_schema(&self, schema: Value) {
+        self.state.lock().unwrap().store.schema = Some(schema);
+    }
+
+    /// Stores `row` in `table` without going through GraphQL and returns its id.
+    pub fn insert_row(&self, table: &str, row: Value) -> Value {
+        let row = self.state.lock().unwrap().store.insert(table, &row).expect("MockDevii rows must be objects");
+        row["id"].clone()
+    }
+
+    /// The bodies of every query request received so far.
+    pub fn queries(&self) -> Vec<Value> {
+        self.state.lock().unwrap().queries.clone()
+    }
+}
+
+impl Drop for MockDevii {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wakes the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    bearer: Option<String>,
+    body: Vec<u8>
+}
+
+#[derive(Clone)]
+struct Server {
+    base: String,
+    state: Arc<Mutex<MockState>>
+}
+
+impl Server {
+    // Answers requests on one keep-alive connection until the client closes it.
+    fn serve(&self, stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(60)));
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return
+        };
+        let mut reader = BufReader::new(stream);
+
+        while let Some(request) = read_request(&mut reader) {
+            let (status, body) = self.respond(request);
+            let reason = match status {
+                200 => "OK",
+                400 => "Bad Request",
+                401 => "Unauthorized",
+                _ => "Not Found"
+            };
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status, reason, body.len(), body
+            );
+            if writer.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn respond(&self, request: Request) -> (u16, Value) {
+        let query_path = format!("/tenant{}/query", MockDevii::TENANT_ID);
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/auth") => self.login(&body),
+            ("GET", "/auth") => self.refresh(request.bearer),
+            ("POST", path) if path == query_path => self.query(request.bearer, body),
+            _ => (404, json!({ "error": "Not found.", "status": 404 }))
+        }
+    }
+
+    fn login(&self, body: &Value) -> (u16, Value) {
+        let authorized = body["login"] == MockDevii::LOGIN
+            && body["password"] == MockDevii::PASSWORD
+            && body["tenantid"] == MockDevii::TENANT_ID;
+        if !authorized {
+            return (401, json!({ "error": "Invalid login credentials.", "status": 401 }));
+        }
+
+        let (access_token, refresh_token) = self.state.lock().unwrap().issue_tokens();
+        let tenant = format!("{}/tenant{}", self.base, MockDevii::TENANT_ID);
+        (200, json!({
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "message": "Logged in.",
+            "routes": {
+                "base": self.base,
+                "query": format!("{}/query", tenant),
+                "roles_pbac": format!("{}/roles_pbac", tenant)
+            }
+        }))
+    }
+
+    fn refresh(&self, bearer: Option<String>) -> (u16, Value) {
+        let mut state = self.state.lock().unwrap();
+        match bearer {
+            Some(token) if state.refresh_tokens.contains(&token) => {
+                state.refreshes += 1;
+                let (access_token, refresh_token) = state.issue_tokens();
+                (200, json!({ "access_token": access_token, "refresh_token": refresh_token }))
+            },
+            _ => (401, json!({ "error": "Invalid refresh token.", "status": 401 }))
+        }
+    }
+
+    fn query(&self, bearer: Option<String>, body: Value) -> (u16, Value) {
+        let mut state = self.state.lock().unwrap();
+
+        match bearer.and_then(|token| state.access_tokens.get(&token).copied()) {
+            Some(false) => {},
+            Some(true) => return (401, json!({ "error": "Token expired.", "status": 401 })),
+            None => return (401, json!({ "error": "Invalid access token.", "status": 401 }))
+        }
+        state.queries.push(body.clone());
+
+        // Batched inserts send their variables as a JSON encoded string.
+        let variables = match &body["variables"] {
+            Value::String(s) => serde_json::from_str(s).unwrap_or(Value::Null),
+            other => other.clone()
+        };
+        let variables = match variables {
+            Value::Object(map) => map,
+            _ => Map::new()
+        };
+
+        match graphql::parse(body["query"].as_str().unwrap_or_default(), &variables, body["operationName"].as_str()) {
+            Ok(operation) => (200, state.store.execute(&operation)),
+            Err(message) => (400, json!({ "errors": [{ "message": message }] }))
+        }
+    }
+}
+
+// Reads one HTTP/1.1 request, `None` once the connection is closed or unreadable.
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    let mut bearer = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().ok()?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                bearer = value.strip_prefix("Bearer ").map(str::to_string);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request { method, path, bearer, body })
+}
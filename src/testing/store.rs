@@ -0,0 +1,299 @@
+//! The in-memory tables behind `MockDevii` and the resolution of GraphQL operations against them.
+//!
+//! Tables are schemaless and created on first use. Relations follow Devii's naming: a row of
+//! `parent` selects its children through `child_collection` (matched on `child.parent_id`) and a
+//! child selects its parent through `parent` (matched on `child.parent_id`).
+
+use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use serde_json::{json, Map, Value};
+
+use crate::testing::filter;
+use crate::testing::graphql::{Field, Operation, OperationKind};
+
+type Row = Map<String, Value>;
+
+#[derive(Debug, Default)]
+struct Table {
+    rows: Vec<Map<String, Value>>,
+    next_id: u64
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    tables: BTreeMap<String, Table>,
+    // What `__schema` resolves to, as tables are schemaless.
+    pub schema: Option<Value>
+}
+
+impl Store {
+    pub fn rows(&self, table: &str) -> Vec<Map<String, Value>> {
+        self.tables.get(table).map(|t| t.rows.clone()).unwrap_or_default()
+    }
+
+    /// Inserts `input` into `table`, assigning the next serial `id` unless one is given.
+    pub fn insert(&mut self, table: &str, input: &Value) -> Result<Map<String, Value>, String> {
+        let mut row = match input {
+            Value::Object(map) => map.clone(),
+            other => return Err(format!("Expected an object as input for {}, found {}", table, other))
+        };
+        let rows = self.tables.entry(table.to_string()).or_default();
+
+        match row.get("id").cloned().unwrap_or(Value::Null) {
+            Value::Null => {
+                rows.next_id += 1;
+                row.insert("id".to_string(), Value::from(rows.next_id));
+            },
+            id => {
+                let id = normalize_id(id);
+                if rows.rows.iter().any(|r| same_id(&r["id"], &id)) {
+                    return Err(format!("duplicate key value violates unique constraint \"{}_pkey\"", table));
+                }
+                if let Some(n) = id.as_u64() {
+                    rows.next_id = rows.next_id.max(n);
+                }
+                row.insert("id".to_string(), id);
+            }
+        }
+
+        rows.rows.push(row.clone());
+        Ok(row)
+    }
+
+    fn update(&mut self, table: &str, key: &Map<String, Value>, input: &Value) -> Result<Option<Map<String, Value>>, String> {
+        let input = match input {
+            Value::Object(map) => map,
+            other => return Err(format!("Expected an object as input for {}, found {}", table, other))
+        };
+        let row = self.tables.get_mut(table)
+            .and_then(|t| t.rows.iter_mut().find(|r| has_key(r, key)));
+
+        Ok(row.map(|row| {
+            for (column, value) in input {
+                if !key.contains_key(column) {
+                    row.insert(column.clone(), value.clone());
+                }
+            }
+            row.clone()
+        }))
+    }
+
+    fn delete(&mut self, table: &str, key: &Map<String, Value>) -> Option<Map<String, Value>> {
+        let rows = &mut self.tables.get_mut(table)?.rows;
+        let index = rows.iter().position(|r| has_key(r, key))?;
+        Some(rows.remove(index))
+    }
+
+    /// Runs every root field of `operation` and builds the response body.
+    pub fn execute(&mut self, operation: &Operation) -> Value {
+        let mut data = Map::new();
+        let mut errors = vec![];
+
+        for field in &operation.selection {
+            let result = match operation.kind {
+                OperationKind::Query => self.resolve_query(field),
+                OperationKind::Mutation => self.resolve_mutation(field)
+            };
+            match result {
+                Ok(value) => {
+                    data.insert(field.key().to_string(), value);
+                },
+                Err(message) => {
+                    data.insert(field.key().to_string(), Value::Null);
+                    errors.push(json!({ "message": message, "path": [field.key()] }));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            json!({ "data": data })
+        } else {
+            json!({ "data": data, "errors": errors })
+        }
+    }
+
+    fn resolve_query(&self, field: &Field) -> Result<Value, String> {
+        if field.name == "__typename" {
+            return Ok(Value::from("Query"));
+        }
+        if field.name == "__schema" {
+            return self.schema.clone().ok_or_else(|| "Introspection is not set up, see MockDevii::set_schema".to_string());
+        }
+        if field.name == "Aggregates" {
+            return self.resolve_aggregates(field);
+        }
+        let rows = self.query_rows(&field.name, &field.arguments, |_| true)?;
+        Ok(Value::Array(rows.into_iter().map(|row| self.select(&field.name, row, &field.selection)).collect()))
+    }
+
+    // `Aggregates { count(subject: "table.column", filter: ..., group_by: [...]) }`. Grouped
+    // aggregates return one `{ group, value }` per group.
+    fn resolve_aggregates(&self, field: &Field) -> Result<Value, String> {
+        let mut output = Map::new();
+        for aggregate in &field.selection {
+            let subject = aggregate.arguments.get("subject").and_then(Value::as_str)
+                .ok_or_else(|| format!("Field \"{}\" argument \"subject\" is required", aggregate.name))?;
+            let (table, column) = subject.split_once('.').ok_or_else(|| format!("Invalid subject \"{}\"", subject))?;
+            let rows = self.query_rows(table, &aggregate.arguments, |_| true)?;
+            let group_by: Vec<&str> = match aggregate.arguments.get("group_by") {
+                Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).map(|s| s.rsplit('.').next().unwrap_or(s)).collect(),
+                _ => vec![]
+            };
+
+            let value = if group_by.is_empty() {
+                aggregate_value(&aggregate.name, column, &rows)?
+            } else {
+                let mut groups: Vec<(Vec<Value>, Vec<&Row>)> = vec![];
+                for row in rows {
+                    let key: Vec<Value> = group_by.iter().map(|c| row.get(*c).cloned().unwrap_or(Value::Null)).collect();
+                    match groups.iter_mut().find(|(k, _)| *k == key) {
+                        Some((_, members)) => members.push(row),
+                        None => groups.push((key, vec![row]))
+                    }
+                }
+                let mut entries = vec![];
+                for (key, members) in groups {
+                    entries.push(json!({ "group": key, "value": aggregate_value(&aggregate.name, column, &members)? }));
+                }
+                Value::Array(entries)
+            };
+            output.insert(aggregate.key().to_string(), value);
+        }
+        Ok(Value::Object(output))
+    }
+
+    fn resolve_mutation(&mut self, field: &Field) -> Result<Value, String> {
+        let input = field.arguments.get("input").cloned().unwrap_or(Value::Null);
+        // Every other argument is a primary key column, e.g. `id` or `hash` and `index`.
+        let mut key = field.arguments.clone();
+        key.remove("input");
+
+        let row = if let Some(table) = field.name.strip_prefix("create_") {
+            Some((table, self.insert(table, &input)?))
+        } else if let Some(table) = field.name.strip_prefix("update_") {
+            self.update(table, &key, &input)?.map(|row| (table, row))
+        } else if let Some(table) = field.name.strip_prefix("delete_") {
+            self.delete(table, &key).map(|row| (table, row))
+        } else {
+            return Err(format!("Cannot query field \"{}\" on type \"Mutation\".", field.name));
+        };
+
+        // A missing record resolves to null, as Devii does.
+        Ok(match row {
+            Some((table, row)) => self.select(table, &row, &field.selection),
+            None => Value::Null
+        })
+    }
+
+    // Rows of `table` matching `keep` and the `filter`/`ordering`/`offset`/`limit` arguments.
+    fn query_rows<'a>(&'a self, table: &str, arguments: &Map<String, Value>, keep: impl Fn(&Map<String, Value>) -> bool) -> Result<Vec<&'a Map<String, Value>>, String> {
+        let mut rows = vec![];
+        for row in self.tables.get(table).map(|t| t.rows.iter()).into_iter().flatten() {
+            let matched = match arguments.get("filter") {
+                Some(Value::String(f)) => filter::matches(f, row)?,
+                _ => true
+            };
+            if matched && keep(row) {
+                rows.push(row);
+            }
+        }
+
+        let ordering: Vec<String> = match arguments.get("ordering") {
+            Some(Value::Array(items)) => items.iter().filter_map(|i| i.as_str().map(str::to_string)).collect(),
+            Some(Value::String(item)) => vec![item.clone()],
+            _ => vec![]
+        };
+        for order in ordering.iter().rev() {
+            let mut parts = order.split_whitespace();
+            let column = parts.next().unwrap_or_default().to_string();
+            let descending = parts.next().map(|d| d.eq_ignore_ascii_case("desc")).unwrap_or(false);
+
+            rows.sort_by(|a, b| {
+                let ordering = order_values(a.get(&column), b.get(&column));
+                if descending { ordering.reverse() } else { ordering }
+            });
+        }
+
+        let offset = arguments.get("offset").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let limit = arguments.get("limit").and_then(Value::as_u64).map(|l| l as usize).unwrap_or(usize::MAX);
+
+        Ok(rows.into_iter().skip(offset).take(limit).collect())
+    }
+
+    fn select(&self, table: &str, row: &Map<String, Value>, selection: &[Field]) -> Value {
+        let mut output = Map::new();
+
+        for field in selection {
+            let value = if field.name == "__typename" {
+                Value::from(table)
+            } else if let Some(value) = row.get(&field.name) {
+                // Devii returns primary keys as GraphQL `ID`s, which are strings.
+                if field.name == "id" && value.is_number() {
+                    Value::String(value.to_string())
+                } else {
+                    value.clone()
+                }
+            } else if let Some(child) = field.name.strip_suffix("_collection") {
+                let fk = format!("{}_id", table);
+                let children = self.query_rows(child, &field.arguments, |c| same_id(c.get(&fk).unwrap_or(&Value::Null), &row["id"]))
+                    .unwrap_or_default();
+                Value::Array(children.into_iter().map(|c| self.select(child, c, &field.selection)).collect())
+            } else if let Some(fk) = row.get(&format!("{}_id", field.name)) {
+                self.tables.get(&field.name)
+                    .and_then(|t| t.rows.iter().find(|r| same_id(&r["id"], fk)))
+                    .map(|parent| self.select(&field.name, parent, &field.selection))
+                    .unwrap_or(Value::Null)
+            } else {
+                Value::Null
+            };
+            output.insert(field.key().to_string(), value);
+        }
+        Value::Object(output)
+    }
+}
+
+// Ids arrive as numbers or as the strings Devii hands out.
+fn normalize_id(id: Value) -> Value {
+    match &id {
+        Value::String(s) => s.parse::<u64>().map(Value::from).unwrap_or(id),
+        _ => id
+    }
+}
+
+fn same_id(a: &Value, b: &Value) -> bool {
+    filter::compare(a, b) == Some(Ordering::Equal)
+}
+
+fn has_key(row: &Map<String, Value>, key: &Map<String, Value>) -> bool {
+    !key.is_empty() && key.iter().all(|(column, value)| same_id(row.get(column).unwrap_or(&Value::Null), value))
+}
+
+// `function` over the non-null values of `column`. Sums of integers stay integers.
+fn aggregate_value(function: &str, column: &str, rows: &[&Map<String, Value>]) -> Result<Value, String> {
+    let values: Vec<&Value> = rows.iter().filter_map(|r| r.get(column)).filter(|v| !v.is_null()).collect();
+    if function != "count" && values.is_empty() {
+        return Ok(Value::Null);
+    }
+    let numbers: Vec<f64> = values.iter().filter_map(|v| v.as_f64()).collect();
+    Ok(match function {
+        "count" => Value::from(values.len()),
+        "sum" if values.iter().all(|v| v.is_i64()) => Value::from(values.iter().filter_map(|v| v.as_i64()).sum::<i64>()),
+        "sum" => Value::from(numbers.iter().sum::<f64>()),
+        "avg" => Value::from(numbers.iter().sum::<f64>() / numbers.len() as f64),
+        "min" => values.into_iter().min_by(|a, b| order_values(Some(a), Some(b))).cloned().unwrap_or(Value::Null),
+        "max" => values.into_iter().max_by(|a, b| order_values(Some(a), Some(b))).cloned().unwrap_or(Value::Null),
+        other => return Err(format!("Cannot query field \"{}\" on type \"Aggregates\".", other))
+    })
+}
+
+// Nulls sort last, as in Postgres.
+fn order_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    let a = a.unwrap_or(&Value::Null);
+    let b = b.unwrap_or(&Value::Null);
+    match (a.is_null(), b.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        _ => filter::compare(a, b).unwrap_or(Ordering::Equal)
+    }
+}
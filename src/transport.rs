@@ -0,0 +1,183 @@
+//! Sending requests to Devii is kept apart from building queries and decoding results, so the
+//! async and blocking flavours of every `DeviiClient` operation only differ in the transport used.
+
+use core::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+use serde_json::Value;
+
+use crate::error::DeviiError;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub bearer: Option<String>,
+    /// Sent as a JSON body.
+    pub body: Option<Value>
+}
+
+impl HttpRequest {
+    pub fn get(url: String, bearer: Option<String>) -> Self {
+        HttpRequest { method: Method::Get, url, bearer, body: None }
+    }
+
+    pub fn post(url: String, bearer: Option<String>, body: Value) -> Self {
+        HttpRequest { method: Method::Post, url, bearer, body: Some(body) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String
+}
+
+/// Sends requests for the async methods of `DeviiClient`.
+pub trait Transport: Debug + Send + Sync {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, DeviiError>>;
+}
+
+/// Sends requests for the `_sync` methods of `DeviiClient`.
+pub trait BlockingTransport: Debug + Send + Sync {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, DeviiError>;
+}
+
+/// Settings of the HTTP connection pool kept by the reqwest transports. Unset values use reqwest's defaults.
+#[derive(Debug, Clone, Builder, Default)]
+#[builder(setter(strip_option))]
+#[builder(default)]
+pub struct HttpOptions {
+    /// Maximum idle connections kept open per host.
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept open.
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    /// Talk HTTP/2 to Devii without negotiating it first.
+    pub(crate) http2_prior_knowledge: bool
+}
+
+impl HttpOptions {
+    pub(crate) fn client(&self) -> Result<reqwest::Client, DeviiError> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(builder.build()?)
+    }
+
+    pub(crate) fn blocking_client(&self) -> Result<reqwest::blocking::Client, DeviiError> {
+        let mut builder = reqwest::blocking::Client::builder();
+
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// The default async transport, one pooled `reqwest::Client` shared by every clone.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: reqwest::Client
+}
+
+impl ReqwestTransport {
+    pub fn new(options: &HttpOptions) -> Result<Self, DeviiError> {
+        Ok(ReqwestTransport { client: options.client()? })
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, DeviiError>> {
+        Box::pin(async move {
+            let mut builder = match request.method {
+                Method::Get => self.client.get(&request.url),
+                Method::Post => self.client.post(&request.url)
+            };
+            if let Some(token) = &request.bearer {
+                builder = builder.header("Authorization", format!("Bearer {}", token));
+            }
+            if let Some(body) = &request.body {
+                builder = builder.json(body);
+            }
+
+            let response = builder.send().await?;
+            let status = response.status().as_u16();
+            let body = response.text().await?;
+
+            Ok(HttpResponse { status, body })
+        })
+    }
+}
+
+/// The default blocking transport, one pooled `reqwest::blocking::Client` shared by every clone.
+#[derive(Debug)]
+pub struct BlockingReqwestTransport {
+    options: HttpOptions,
+    // Built on first use, as a blocking client can't be created or dropped inside an async runtime.
+    client: OnceLock<reqwest::blocking::Client>
+}
+
+impl BlockingReqwestTransport {
+    pub fn new(options: &HttpOptions) -> Self {
+        BlockingReqwestTransport {
+            options: options.clone(),
+            client: OnceLock::new()
+        }
+    }
+
+    fn client(&self) -> Result<&reqwest::blocking::Client, DeviiError> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = self.options.blocking_client()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+impl BlockingTransport for BlockingReqwestTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, DeviiError> {
+        let client = self.client()?;
+
+        let mut builder = match request.method {
+            Method::Get => client.get(&request.url),
+            Method::Post => client.post(&request.url)
+        };
+        if let Some(token) = &request.bearer {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        if let Some(body) = &request.body {
+            builder = builder.json(body);
+        }
+
+        let response = builder.send()?;
+        let status = response.status().as_u16();
+        let body = response.text()?;
+
+        Ok(HttpResponse { status, body })
+    }
+}
@@ -0,0 +1,247 @@
+//! Insert or update by key. Devii has no upsert mutation, so the existing records are looked up
+//! first and each object then becomes a `create_` or an `update_` of one `MutationBatch`.
+//!
+//! The lookup and the mutations are separate requests: a record inserted by someone else in
+//! between isn't seen, and the insert then fails on the table's unique constraint if there is one.
+
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::batch::{take_all, MutationBatch, MutationHandle, MutationResults};
+use crate::devii::{DeviiClient, DeviiQueryResult, DeviiTrait, Operation};
+use crate::error::DeviiError;
+use crate::filter::{col, Filter, FilterValue};
+use crate::id::read_key;
+
+/// The record `upsert` wrote, and whether it was created or updated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upsert<T> {
+    Created(T),
+    Updated(T)
+}
+
+impl<T> Upsert<T> {
+    pub fn is_created(&self) -> bool {
+        matches!(self, Upsert::Created(_))
+    }
+
+    pub fn record(&self) -> &T {
+        match self {
+            Upsert::Created(record) | Upsert::Updated(record) => record
+        }
+    }
+
+    pub fn into_record(self) -> T {
+        match self {
+            Upsert::Created(record) | Upsert::Updated(record) => record
+        }
+    }
+}
+
+impl DeviiClient {
+    /// Updates the record whose `key_fields` columns equal those of `object`, or inserts `object`
+    /// if there is none. Fails with `InvalidInput` if the key matches more than one record.
+    ///
+    /// An object whose key has a null column is always inserted, as null never equals anything.
+    pub async fn upsert<T: DeviiTrait>(&self, object: &T, key_fields: &[&str]) -> Result<Upsert<T>, DeviiError> {
+        let mut results = self.batch_upsert(std::slice::from_ref(object), key_fields).await?;
+        results.pop().ok_or(DeviiError::MissingData { field: T::table_name() })
+    }
+
+    pub fn upsert_sync<T: DeviiTrait>(&self, object: &T, key_fields: &[&str]) -> Result<Upsert<T>, DeviiError> {
+        let mut results = self.batch_upsert_sync(std::slice::from_ref(object), key_fields)?;
+        results.pop().ok_or(DeviiError::MissingData { field: T::table_name() })
+    }
+
+    /// `upsert` of every object, in two requests: one looking up all the keys and one with every
+    /// insert and update. Returns the records in the order of `objects`. If any insert or update
+    /// fails, every error is returned and the ones that succeeded stay committed.
+    pub async fn batch_upsert<T: DeviiTrait>(&self, objects: &[T], key_fields: &[&str]) -> Result<Vec<Upsert<T>>, DeviiError> {
+        if objects.is_empty() {
+            return Ok(vec![]);
+        }
+        let lookup = Lookup::new(objects, key_fields)?;
+        let ids = match lookup.operation()? {
+            Some(operation) => self.run(operation).await?,
+            None => vec![]
+        };
+        let (batch, handles) = upsert_batch(objects, lookup.resolve(ids)?)?;
+        finish(self.submit_batch(batch).await?, handles)
+    }
+
+    pub fn batch_upsert_sync<T: DeviiTrait>(&self, objects: &[T], key_fields: &[&str]) -> Result<Vec<Upsert<T>>, DeviiError> {
+        if objects.is_empty() {
+            return Ok(vec![]);
+        }
+        let lookup = Lookup::new(objects, key_fields)?;
+        let ids = match lookup.operation()? {
+            Some(operation) => self.run_sync(operation)?,
+            None => vec![]
+        };
+        let (batch, handles) = upsert_batch(objects, lookup.resolve(ids)?)?;
+        finish(self.submit_batch_sync(batch)?, handles)
+    }
+}
+
+// The filter selecting each object's existing record, `None` for objects that are always inserted.
+struct Lookup<T: DeviiTrait> {
+    filters: Vec<Option<Filter>>,
+    table: PhantomData<T>
+}
+
+// The keys of the records found for each looked up object, by index in the input.
+type LookupOperation<I> = Operation<DeviiQueryResult<Vec<Map<String, Value>>>, Vec<(usize, Vec<I>)>>;
+
+#[derive(Serialize, Debug)]
+struct LookupQuery {
+    query: String,
+    variables: Map<String, Value>
+}
+
+impl<T: DeviiTrait> Lookup<T> {
+    fn new(objects: &[T], key_fields: &[&str]) -> Result<Self, DeviiError> {
+        if key_fields.is_empty() {
+            return Err(DeviiError::InvalidInput("upsert needs at least one key field".to_string()));
+        }
+        let filters = objects.iter()
+            .map(|object| key_filter(object, key_fields))
+            .collect::<Result<_, _>>()?;
+        Ok(Lookup { filters, table: PhantomData })
+    }
+
+    // One aliased query per looked up object. Two rows are asked for to detect ambiguous keys.
+    fn operation(&self) -> Result<Option<LookupOperation<T::Id>>, DeviiError> {
+        let table = T::table_name();
+        let columns = T::id_columns();
+        let mut definitions = vec![];
+        let mut fields = vec![];
+        let mut variables = Map::new();
+        let mut indices = vec![];
+
+        for (index, filter) in self.filters.iter().enumerate() {
+            if let Some(filter) = filter {
+                definitions.push(format!("$filter_{}: String", index));
+                fields.push(format!("key_{}: {} (filter: $filter_{}, limit: 2){{ {} }}", index, table, index, columns.join(" ")));
+                variables.insert(format!("filter_{}", index), Value::from(filter.to_string()));
+                indices.push(index);
+            }
+        }
+        if indices.is_empty() {
+            return Ok(None);
+        }
+
+        let query = LookupQuery {
+            query: format!("query lookup ({}){{\n        {}\n      }}", definitions.join(", "), fields.join("\n        ")),
+            variables
+        };
+        Operation::new(&query, move |mut result: DeviiQueryResult<Vec<Map<String, Value>>>| {
+            indices.into_iter().map(|index| {
+                let rows = result.take(&format!("key_{}", index))?;
+                let ids = rows.iter()
+                    .map(|row| read_key(&columns, row))
+                    .collect::<Result<_, _>>()?;
+                Ok((index, ids))
+            }).collect()
+        }).map(Some)
+    }
+
+    // The id of the existing record of every object, `None` where it has to be inserted.
+    fn resolve(&self, found: Vec<(usize, Vec<T::Id>)>) -> Result<Vec<Option<T::Id>>, DeviiError> {
+        let mut ids = vec![None; self.filters.len()];
+        for (index, mut matches) in found {
+            if matches.len() > 1 {
+                return Err(DeviiError::InvalidInput(format!(
+                    "upsert key `{}` matches more than one {} record",
+                    self.filters[index].as_ref().map(|f| f.to_string()).unwrap_or_default(),
+                    T::table_name()
+                )));
+            }
+            ids[index] = matches.pop();
+        }
+        Ok(ids)
+    }
+}
+
+// `key = value and ...` over `key_fields`, read from the object's inputs or, for read only
+// columns such as the id, its serialized form.
+fn key_filter<T: DeviiTrait>(object: &T, key_fields: &[&str]) -> Result<Option<Filter>, DeviiError> {
+    let inputs = object.graphql_inputs();
+    let serialized = serde_json::to_value(object)?;
+    let fields = T::fields();
+
+    let mut filter: Option<Filter> = None;
+    for key in key_fields {
+        let value = inputs.get(*key)
+            .or_else(|| {
+                let name = fields.iter().find(|f| f.graphql_name == *key).map(|f| f.name).unwrap_or(*key);
+                serialized.get(name)
+            })
+            .ok_or_else(|| DeviiError::InvalidInput(format!("{} has no key field `{}`", T::table_name(), key)))?;
+
+        let condition = match FilterValue::from_json(value) {
+            Some(FilterValue::Null) => return Ok(None),
+            Some(value) => col(*key).eq(value),
+            None => return Err(DeviiError::InvalidInput(format!("key field `{}` must be a scalar, found {}", key, value)))
+        };
+        filter = Some(match filter {
+            Some(filter) => filter.and(condition),
+            None => condition
+        });
+    }
+    Ok(filter)
+}
+
+// Each handle with whether it creates the record.
+type UpsertHandles<T> = Vec<(bool, MutationHandle<T>)>;
+
+fn upsert_batch<T: DeviiTrait>(objects: &[T], ids: Vec<Option<T::Id>>) -> Result<(MutationBatch, UpsertHandles<T>), DeviiError> {
+    let mut batch = MutationBatch::new();
+    let mut handles = vec![];
+    for (object, id) in objects.iter().zip(ids) {
+        handles.push(match id {
+            Some(id) => (false, batch.update(object, id)),
+            None => (true, batch.insert_record(object)?)
+        });
+    }
+    Ok((batch, handles))
+}
+
+fn finish<T>(results: MutationResults, handles: UpsertHandles<T>) -> Result<Vec<Upsert<T>>, DeviiError> {
+    let (created, handles): (Vec<bool>, Vec<MutationHandle<T>>) = handles.into_iter().unzip();
+    let records = take_all(results, &handles)?;
+    Ok(created.into_iter().zip(records)
+        .map(|(created, record)| if created { Upsert::Created(record) } else { Upsert::Updated(record) })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeviiError;
+    use crate::test_struct::{connect, TestStruct};
+    use crate::upsert::Upsert;
+
+    #[test]
+    fn upsert_test() {
+        let (server, client) = connect();
+        let existing = TestStruct { string: "existing".to_string(), ..TestStruct::new() };
+        server.insert_row("test_struct", serde_json::to_value(&existing).unwrap());
+
+        let changed = TestStruct { _u8: 42, ..existing };
+        let created = TestStruct { string: "created".to_string(), ..TestStruct::new() };
+        let results = tokio_test::block_on(client.batch_upsert(&[changed, created], &["string"])).unwrap();
+        assert!(matches!(&results[0], Upsert::Updated(r) if r.id == Some(1) && r._u8 == 42));
+        assert!(matches!(&results[1], Upsert::Created(r) if r.id == Some(2) && r.string == "created"));
+        assert_eq!(server.rows("test_struct").len(), 2);
+        assert_eq!(server.rows("test_struct")[0]["_u8"], 42);
+
+        let again = client.upsert_sync(&TestStruct { string: "created".to_string(), ..TestStruct::new() }, &["string", "_u8"]).unwrap();
+        assert!(!again.is_created());
+        assert_eq!(again.into_record().id, Some(2));
+
+        server.insert_row("test_struct", serde_json::json!({ "string": "created", "_u8": 0 }));
+        let ambiguous = client.upsert_sync(&TestStruct { string: "created".to_string(), ..TestStruct::new() }, &["string"]);
+        assert!(matches!(ambiguous, Err(DeviiError::InvalidInput(_))));
+        assert!(matches!(client.upsert_sync(&TestStruct::new(), &["missing"]), Err(DeviiError::InvalidInput(_))));
+    }
+}